@@ -0,0 +1,148 @@
+use unicode_normalization::UnicodeNormalization;
+
+pub const USER_ID_MIN_LEN: usize = 3;
+pub const USER_ID_MAX_LEN: usize = 24;
+pub const PASSWORD_MIN_LEN: usize = 8;
+/// upper bound keeps hashing cost predictable
+pub const PASSWORD_MAX_LEN: usize = 128;
+
+/// names that could be mistaken for the server or its operators, compared after normalizing
+const RESERVED_USER_IDS: &[&str] = &[
+    "admin",
+    "administrator",
+    "root",
+    "system",
+    "server",
+    "moderator",
+    "mod",
+    "support",
+    "staff",
+    "host",
+    "null",
+    "undefined",
+];
+
+/// passwords too common to be accepted regardless of length
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "password1",
+    "passw0rd",
+    "12345678",
+    "123456789",
+    "1234567890",
+    "qwertyuiop",
+    "qwerty123",
+    "iloveyou",
+    "letmein1",
+    "football",
+    "baseball",
+    "sunshine",
+    "princess",
+    "11111111",
+    "00000000",
+    "abcdefgh",
+    "abc12345",
+];
+
+/// normalize user ID (NFKC, surrounding whitespace trimmed, lowercased)
+/// so visually identical IDs and IDs differing only in case collide
+pub fn normalize_user_id(user_id: &str) -> String {
+    user_id.trim().nfkc().collect::<String>().to_lowercase()
+}
+
+/// normalize then validate user ID length, charset and reserved names
+/// returns normalized user ID that should be used as the account key
+pub fn validate_user_id(user_id: &str) -> Result<String, String> {
+    let user_id = normalize_user_id(user_id);
+    let len = user_id.chars().count();
+    if !(USER_ID_MIN_LEN..=USER_ID_MAX_LEN).contains(&len) {
+        return Err(format!(
+            "user id must be {} to {} characters",
+            USER_ID_MIN_LEN, USER_ID_MAX_LEN
+        ));
+    }
+    if !user_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("user id may only contain letters, digits, '_' and '-'".into());
+    }
+    if !user_id.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err("user id must start with a letter or digit".into());
+    }
+    if RESERVED_USER_IDS.contains(&user_id.as_str()) {
+        return Err(format!("user id {} is reserved", user_id));
+    }
+    Ok(user_id)
+}
+
+/// check password is long enough, varied and not trivially guessable
+pub fn check_password_strength(user_id: &str, password: &str) -> Result<(), String> {
+    let len = password.chars().count();
+    if len < PASSWORD_MIN_LEN {
+        return Err(format!(
+            "password must be at least {} characters",
+            PASSWORD_MIN_LEN
+        ));
+    }
+    if len > PASSWORD_MAX_LEN {
+        return Err(format!(
+            "password must be at most {} characters",
+            PASSWORD_MAX_LEN
+        ));
+    }
+    let lower = password.to_lowercase();
+    if lower.contains(&user_id.to_lowercase()) {
+        return Err("password must not contain user id".into());
+    }
+    if COMMON_PASSWORDS.contains(&lower.as_str()) {
+        return Err("password is too common".into());
+    }
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|c| **c).count() < 2 {
+        return Err(
+            "password must mix at least 2 of lowercase, uppercase, digits and symbols".into(),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_user_id() {
+        assert_eq!(validate_user_id("alice"), Ok("alice".into()));
+        assert_eq!(validate_user_id("  bob_42 "), Ok("bob_42".into()));
+        // fullwidth forms normalize to ascii
+        assert_eq!(validate_user_id("ａｌｉｃｅ"), Ok("alice".into()));
+        assert!(validate_user_id("al").is_err());
+        assert!(validate_user_id(&"a".repeat(USER_ID_MAX_LEN + 1)).is_err());
+        assert!(validate_user_id("al ice").is_err());
+        assert!(validate_user_id("alicé").is_err());
+        assert!(validate_user_id("_alice").is_err());
+        assert!(validate_user_id("Admin").is_err());
+        assert!(validate_user_id("ＡＤＭＩＮ").is_err());
+        // IDs differing only in case are the same account
+        assert_eq!(validate_user_id("Alice"), Ok("alice".into()));
+        assert_eq!(validate_user_id("ＡＬＩＣＥ"), Ok("alice".into()));
+        assert_eq!(normalize_user_id(" ALICE"), "alice");
+    }
+
+    #[test]
+    fn test_check_password_strength() {
+        assert_eq!(check_password_strength("alice", "correct horse"), Ok(()));
+        assert_eq!(check_password_strength("alice", "Tr0ub4dor"), Ok(()));
+        assert!(check_password_strength("alice", "short1").is_err());
+        assert!(check_password_strength("alice", "alllowercase").is_err());
+        assert!(check_password_strength("alice", "Password1").is_err());
+        assert!(check_password_strength("alice", "xALICEx99").is_err());
+        assert!(check_password_strength("alice", &"a1".repeat(PASSWORD_MAX_LEN)).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use actix::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::Display;
+
+use crate::common::{ConfigGameOp, Identity, MsgResult};
+use crate::game::{GameFilter, PlayerAction};
+use crate::matchmaking::MatchPrefs;
+use crate::protocol::{ErrorPayload, ProtocolError, Request, PREFERENTIAL_VOTES};
+use crate::relay_server::{
+    CancelMatch, ConfigGame, Connect, ConnectResult, CreateInvite, FindMatch, GameState, HostGame,
+    JoinGame, Message, PlayerActionRequest, RefreshToken, Register, RelayServer, Resume,
+    RevokeToken, StartGame, UserStatus, VerifySession, WatchLobby,
+};
+
+/// client session a command is run for
+#[derive(Clone)]
+pub struct Client {
+    pub server: Addr<RelayServer>,
+    /// receives pushes from the relay server
+    pub addr: Recipient<Message>,
+    pub user_id: Option<String>,
+    pub ip: Option<String>,
+    /// optional features negotiated with `/hello`, see `protocol::FEATURES`
+    pub features: Vec<String>,
+}
+
+/// successful command
+#[derive(Debug)]
+pub struct Ack {
+    /// ack payload for v2 clients
+    pub payload: Value,
+    /// reply for legacy clients, which mostly learn of success from pushed updates
+    pub legacy: Option<String>,
+    /// user the session is now logged in as
+    pub user_id: Option<String>,
+}
+
+/// failed command, `context` is the legacy `/error context: message` context
+pub type Nack = ErrorPayload;
+
+impl Ack {
+    fn empty() -> Ack {
+        Ack {
+            payload: Value::Null,
+            legacy: None,
+            user_id: None,
+        }
+    }
+
+    fn payload<T: Serialize>(payload: &T) -> Result<Ack, Nack> {
+        let payload = serde_json::to_value(payload).map_err(|e| internal("server", e))?;
+        Ok(Ack {
+            payload,
+            legacy: None,
+            user_id: None,
+        })
+    }
+}
+
+fn nack<E: Serialize + Display>(context: &str, e: E) -> Nack {
+    ErrorPayload::new(context, &e)
+}
+
+fn internal<E: ToString>(context: &str, e: E) -> Nack {
+    nack(context, ProtocolError::Internal(e.to_string()))
+}
+
+pub fn mailbox_error(e: MailboxError) -> Nack {
+    log::debug!("{:?}", e);
+    internal("server", "mailbox error")
+}
+
+/// game ID or invite code, or game ID and password for `Password` visible games
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JoinGameArgs {
+    GameId(String),
+    WithPassword {
+        game_id: String,
+        password: Option<String>,
+    },
+}
+
+/// game ID, or game ID and invite options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CreateInviteArgs {
+    GameId(String),
+    WithOptions {
+        game_id: String,
+        ttl_secs: Option<u64>,
+        #[serde(default)]
+        single_use: bool,
+    },
+}
+
+#[derive(Deserialize)]
+struct ConfGameArgs {
+    game_id: String,
+    op: ConfigGameOp,
+}
+
+/// reply to `Connect`, `Register` or `Attach`, logging the session in on success
+pub fn connect_ack(res: ConnectResult) -> Result<Ack, Nack> {
+    match res {
+        ConnectResult::Fail(fail) => Err(nack("user", fail)),
+        ConnectResult::Success(s) => {
+            let mut ack = Ack::payload(&s)?;
+            ack.legacy = Some(MsgResult::login(&s).map_err(|e| internal("login", e))?);
+            ack.user_id = Some(s.user_id);
+            Ok(ack)
+        }
+    }
+}
+
+/// run client request against the relay server
+pub async fn dispatch(client: Client, req: Request) -> Result<Ack, Nack> {
+    let server = client.server.clone();
+    let user_id = || {
+        client
+            .user_id
+            .clone()
+            .ok_or_else(|| nack("session", ProtocolError::NotLoggedIn))
+    };
+    let arg = || req.payload::<String>().map_err(|e| nack("session", e));
+    match req.kind.as_str() {
+        "login" => {
+            let id = req.payload::<Identity>().map_err(|e| nack("session", e))?;
+            let res = server
+                .send(Connect {
+                    addr: Some(client.addr.clone()),
+                    user_id: id.user_id,
+                    password: id.password,
+                    ip: client.ip.clone(),
+                })
+                .await
+                .map_err(mailbox_error)?;
+            connect_ack(res)
+        }
+        "register" => {
+            let id = req.payload::<Identity>().map_err(|e| nack("session", e))?;
+            let res = server
+                .send(Register {
+                    addr: Some(client.addr.clone()),
+                    user_id: id.user_id,
+                    password: id.password,
+                })
+                .await
+                .map_err(mailbox_error)?;
+            connect_ack(res)
+        }
+        "verify" => {
+            // rejected sessions are logged out by the relay server
+            let user_id = server
+                .send(VerifySession {
+                    user_id: client.user_id.clone(),
+                    addr: client.addr.clone(),
+                    token: arg()?,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("verify", e))?;
+            let mut ack = Ack::payload(&serde_json::json!({ "user_id": user_id }))?;
+            ack.user_id = Some(user_id);
+            Ok(ack)
+        }
+        "refresh_token" => {
+            let token = server
+                .send(RefreshToken {
+                    user_id: user_id()?,
+                    token: arg()?,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("refresh_token", e))?;
+            let mut ack = Ack::payload(&token)?;
+            ack.legacy =
+                Some(MsgResult::refresh_token(&token).map_err(|e| internal("refresh_token", e))?);
+            Ok(ack)
+        }
+        "revoke_token" | "revoke_all_tokens" => {
+            let token = match req.kind.as_str() {
+                "revoke_token" => Some(arg()?),
+                _ => None,
+            };
+            server
+                .send(RevokeToken {
+                    user_id: user_id()?,
+                    token,
+                })
+                .await
+                .map_err(mailbox_error)?;
+            Ok(Ack::empty())
+        }
+        "resume" => {
+            // missed pushes are resent to this session before the ack
+            let last_seq = req.payload::<u64>().map_err(|e| nack("resume", e))?;
+            let replayed = server
+                .send(Resume {
+                    user_id: user_id()?,
+                    addr: client.addr.clone(),
+                    last_seq,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("resume", e))?;
+            Ack::payload(&serde_json::json!({ "replayed": replayed }))
+        }
+        "host_game" => {
+            server
+                .send(HostGame {
+                    game_id: arg()?,
+                    host_user_id: user_id()?,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("server", e))?;
+            Ok(Ack::empty())
+        }
+        "join_game" => {
+            let (game_id, password) = match req.payload::<JoinGameArgs>() {
+                Ok(JoinGameArgs::GameId(game_id)) => (game_id, None),
+                Ok(JoinGameArgs::WithPassword { game_id, password }) => (game_id, password),
+                Err(e) => return Err(nack("session", e)),
+            };
+            server
+                .send(JoinGame {
+                    game_id,
+                    user_id: user_id()?,
+                    password,
+                    ip: client.ip.clone(),
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("server", e))?;
+            Ok(Ack::empty())
+        }
+        "create_invite" => {
+            let (game_id, ttl_secs, single_use) = match req.payload::<CreateInviteArgs>() {
+                Ok(CreateInviteArgs::GameId(game_id)) => (game_id, None, false),
+                Ok(CreateInviteArgs::WithOptions {
+                    game_id,
+                    ttl_secs,
+                    single_use,
+                }) => (game_id, ttl_secs, single_use),
+                Err(e) => return Err(nack("session", e)),
+            };
+            let invite = server
+                .send(CreateInvite {
+                    user_id: user_id()?,
+                    game_id,
+                    ttl_secs,
+                    single_use,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("create_invite", e))?;
+            let mut ack = Ack::payload(&invite)?;
+            ack.legacy =
+                Some(MsgResult::create_invite(&invite).map_err(|e| internal("create_invite", e))?);
+            Ok(ack)
+        }
+        "conf_game" => {
+            let user_id = user_id()?;
+            let args = req
+                .payload::<ConfGameArgs>()
+                .map_err(|e| nack("session", e))?;
+            server
+                .send(ConfigGame {
+                    game_id: args.game_id,
+                    op: args.op,
+                    user_id,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("conf_game", e))?;
+            Ok(Ack::empty())
+        }
+        "start_game" => {
+            server
+                .send(StartGame {
+                    game_id: arg()?,
+                    user_id: user_id()?,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("server", e))?;
+            Ok(Ack::empty())
+        }
+        "game_state" => {
+            let state = server
+                .send(GameState {
+                    user_id: user_id()?,
+                    game_id: arg()?,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("game_state", e))?;
+            let mut ack = Ack::payload(&state)?;
+            ack.legacy =
+                Some(MsgResult::game_state(&state).map_err(|e| internal("game_state", e))?);
+            Ok(ack)
+        }
+        "user_status" => {
+            // legacy clients get the status pushed to every session of the user
+            let status = server
+                .send(UserStatus {
+                    user_id: user_id()?,
+                })
+                .await
+                .map_err(mailbox_error)?;
+            Ack::payload(&status)
+        }
+        "find_match" => {
+            // legacy `/find_match` has an empty payload
+            let prefs = match &req.payload {
+                Value::Null => MatchPrefs::default(),
+                Value::String(s) if s.is_empty() => MatchPrefs::default(),
+                _ => req
+                    .payload::<MatchPrefs>()
+                    .map_err(|e| nack("find_match", e))?,
+            };
+            let status = server
+                .send(FindMatch {
+                    user_id: user_id()?,
+                    prefs,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("find_match", e))?;
+            let mut ack = Ack::payload(&status)?;
+            ack.legacy =
+                Some(MsgResult::find_match(&status).map_err(|e| internal("find_match", e))?);
+            Ok(ack)
+        }
+        "cancel_match" => {
+            let cancelled = server
+                .send(CancelMatch {
+                    user_id: user_id()?,
+                })
+                .await
+                .map_err(mailbox_error)?;
+            Ack::payload(&serde_json::json!({ "cancelled": cancelled }))
+        }
+        "list_games" => {
+            // legacy `/list_games` has an empty payload
+            let filter = match &req.payload {
+                Value::Null => GameFilter::default(),
+                Value::String(s) if s.is_empty() => GameFilter::default(),
+                _ => req
+                    .payload::<GameFilter>()
+                    .map_err(|e| nack("list_games", e))?,
+            };
+            let games = server
+                .send(WatchLobby {
+                    addr: client.addr.clone(),
+                    filter,
+                })
+                .await
+                .map_err(mailbox_error)?;
+            let mut ack = Ack::payload(&games)?;
+            ack.legacy =
+                Some(MsgResult::list_games(&games).map_err(|e| internal("list_games", e))?);
+            Ok(ack)
+        }
+        "player_action" => {
+            let user_id = user_id()?;
+            let des = req
+                .payload::<PlayerAction>()
+                .map_err(|e| nack("session", e))?;
+            if des.action.is_preferential()
+                && !client.features.iter().any(|f| f == PREFERENTIAL_VOTES)
+            {
+                let e = format!("ranking players needs the {} feature", PREFERENTIAL_VOTES);
+                return Err(nack("player_action", ProtocolError::BadRequest(e)));
+            }
+            server
+                .send(PlayerActionRequest {
+                    action: des.action,
+                    game_id: des.game_id,
+                    user_id,
+                })
+                .await
+                .map_err(mailbox_error)?
+                .map_err(|e| nack("player_action", e))?;
+            Ok(Ack::empty())
+        }
+        kind => Err(nack(
+            "session",
+            ProtocolError::UnknownCommand(kind.to_owned()),
+        )),
+    }
+}
@@ -1,10 +1,16 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 use rand::Rng;
 
 use serde::{Deserialize, Serialize};
 
-use crate::game::{Game, Player, PlayerResponse, PlayersAliveDead, Pos};
+use crate::game::{
+    Game, GameSummary, GameUpdate, Player, PlayerGameState, PlayerResponse, PlayersAliveDead, Pos,
+};
+use crate::invite::Invite;
+use crate::protocol::HelloReply;
+use crate::token::{SessionToken, TokenRejection};
 
 #[derive(Deserialize)]
 pub struct Identity {
@@ -12,15 +18,59 @@ pub struct Identity {
     pub password: String,
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone, Serialize)]
 pub struct SuccessResult {
+    /// normalized user ID the session is logged in as
+    pub user_id: String,
     pub token: Option<String>,
+    pub token_expires_unix: Option<u64>,
     pub alert: String,
 }
 
-#[derive(Clone, Debug)]
+// token is left out so logging a login never leaks it
+impl std::fmt::Debug for SuccessResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("SuccessResult")
+            .field("user_id", &self.user_id)
+            .field("token_expires_unix", &self.token_expires_unix)
+            .field("alert", &self.alert)
+            .finish()
+    }
+}
+
+/// reason a login or registration failed, serialized as `{code, details}`
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
 pub enum Fail {
+    #[serde(rename = "wrong_password")]
     Password,
+    /// no account exists with the user ID, see `Register`
+    UnknownUser,
+    /// registration attempted with a user ID that is taken
+    UserExists,
+    InvalidUserId(String),
+    WeakPassword(String),
+    /// too many failed attempts for user or IP, try again after lockout
+    Locked {
+        retry_after_secs: u64,
+    },
+    /// server failed to read or write the user's account
+    Internal,
+    /// session token presented instead of a password was rejected
+    #[serde(untagged)]
+    Token(TokenRejection),
+}
+
+impl Display for Fail {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Fail::InvalidUserId(reason) => write!(f, "FailInvalidUserId {}", reason),
+            Fail::WeakPassword(reason) => write!(f, "FailWeakPassword {}", reason),
+            Fail::Token(rejection) => write!(f, "FailToken {}", rejection),
+            Fail::Locked { retry_after_secs } => write!(f, "FailLocked {}", retry_after_secs),
+            _ => write!(f, "Fail{:?}", self),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -28,6 +78,31 @@ pub struct UserStatusResult {
     pub game_id: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+struct InviteRedeemed<'a> {
+    invite: &'a Invite,
+    user_id: &'a str,
+}
+
+/// user's place in the matchmaking queue
+#[derive(Clone, Debug, Serialize)]
+pub struct MatchStatus {
+    /// game the user was matched into, the user is no longer queued
+    pub game_id: Option<String>,
+    /// users queued with prefs compatible with the user's, including the user
+    pub waiting: usize,
+}
+
+/// what anyone can see of a user
+#[derive(Clone, Debug, Serialize)]
+pub struct UserProfileResult {
+    pub user_id: String,
+    /// user has a live session
+    pub online: bool,
+    /// game the user is playing or waiting in
+    pub game_id: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct ActionPointUpdate {
     pub user_id: String,
@@ -51,6 +126,18 @@ pub enum InitPosConfig {
     // RandomBlind,
     // ManualSecret,
 }
+/// who can find and join a game
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Visibility {
+    /// listed in the lobby, anyone can join
+    Public,
+    /// anyone who knows the game ID can join
+    Unlisted,
+    /// listed in the lobby, joining takes the game's password
+    Password,
+    /// joining takes an invite code from the host
+    Private,
+}
 #[derive(Debug, Clone, Deserialize)]
 pub enum ConfigGameOp {
     TurnTimeSecs(u64),
@@ -60,11 +147,17 @@ pub enum ConfigGameOp {
     InitRange(usize),
     InitActPts(u32),
     InitPos(InitPosConfig),
+    /// `Password` needs a password set first
+    Visibility(Visibility),
+    /// set password and make the game `Password` visible, the relay swaps in its hash
+    Password(String),
 }
 
+/// full game for legacy clients, which can't apply `/game_update` patches
 #[derive(Debug, Clone, Serialize)]
 struct GameConfigResult<'a> {
     game: &'a Game,
+    /// players moved by the change
     result: &'a Option<HashMap<String, String>>,
 }
 
@@ -98,14 +191,35 @@ impl MsgResult {
             .or_else(|err| Err(format!("{:?}", err)))
     }
 
+    pub fn hello(reply: &HelloReply) -> Result<String, String> {
+        MsgResult::json_string("/hello", reply)
+    }
+
     pub fn login(msg: &SuccessResult) -> Result<String, String> {
         MsgResult::json_string("/login", msg)
     }
 
+    pub fn refresh_token(token: &SessionToken) -> Result<String, String> {
+        MsgResult::json_string("/refresh_token_success", token)
+    }
+
     pub fn logout(msg: &str) -> String {
         format!("/logout {}", msg).into()
     }
 
+    /// first event of an event stream, commands for the stream are posted to `/events/{stream_id}`
+    pub fn event_stream(stream_id: &str) -> String {
+        format!(
+            "/event_stream {}",
+            serde_json::json!({ "stream_id": stream_id })
+        )
+    }
+
+    /// messages from `seq` on were skipped, `/resume` from `seq - 1` to get them
+    pub fn lagged(seq: u64) -> String {
+        format!("/lagged {}", seq)
+    }
+
     pub fn host_game(game: &Game) -> Result<String, String> {
         MsgResult::json_string("/host_game_success", game)
     }
@@ -114,6 +228,21 @@ impl MsgResult {
         MsgResult::json_string("/join_game_success", game)
     }
 
+    pub fn create_invite(invite: &Invite) -> Result<String, String> {
+        MsgResult::json_string("/create_invite", invite)
+    }
+
+    /// sent to the host when `user_id` joins with their invite
+    pub fn invite_redeemed(invite: &Invite, user_id: &str) -> Result<String, String> {
+        let redeemed = InviteRedeemed { invite, user_id };
+        MsgResult::json_string("/invite_redeemed", &redeemed)
+    }
+
+    /// matchmaking put the user in `game`, which starts straight away
+    pub fn match_found(game: &Game) -> Result<String, String> {
+        MsgResult::json_string("/match_found", game)
+    }
+
     pub fn joined(json: &Player) -> Result<String, String> {
         MsgResult::json_string("/player_joined", json)
     }
@@ -126,10 +255,15 @@ impl MsgResult {
         MsgResult::json_string("/conf_game", &res)
     }
 
+    /// full game for legacy clients, which can't apply `/game_update` patches
     pub fn start_game(game: &Game) -> Result<String, String> {
         MsgResult::json_string("/start_game", game)
     }
 
+    pub fn game_update(update: &GameUpdate) -> Result<String, String> {
+        MsgResult::json_string("/game_update", update)
+    }
+
     pub fn tile_hearts(game_id: &str, set: (Pos, u32)) -> Result<String, String> {
         MsgResult::json_string(
             "/tile_hearts_update",
@@ -152,6 +286,31 @@ impl MsgResult {
         MsgResult::json_string("/turn_end_unix", &res)
     }
 
+    pub fn game_state(state: &PlayerGameState) -> Result<String, String> {
+        MsgResult::json_string("/game_state", state)
+    }
+
+    pub fn find_match(status: &MatchStatus) -> Result<String, String> {
+        MsgResult::json_string("/find_match", status)
+    }
+
+    pub fn list_games(games: &[GameSummary]) -> Result<String, String> {
+        MsgResult::json_string("/list_games", &games)
+    }
+
+    /// listed game was created or changed
+    pub fn lobby_game(game: &GameSummary) -> Result<String, String> {
+        MsgResult::json_string("/lobby_game", game)
+    }
+
+    /// game no longer listed, it filled up, started or was made unlisted
+    pub fn lobby_remove(game_id: &str) -> String {
+        format!(
+            "/lobby_remove {}",
+            serde_json::json!({ "game_id": game_id })
+        )
+    }
+
     pub fn user_status(user_status: &UserStatusResult) -> Result<String, String> {
         MsgResult::json_string("/user_status", user_status)
     }
@@ -0,0 +1,402 @@
+use actix_web::http::{HeaderName, Method};
+use serde::Deserialize;
+use std::fs;
+use std::net::ToSocketAddrs;
+use toml::Value;
+
+use crate::game::{GameConfig, BOARD_SIZE};
+use crate::login_guard::LoginGuardConfig;
+use crate::matchmaking::MatchmakingConfig;
+use crate::password::PasswordConfig;
+use crate::protocol::ProtocolConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::relay_server::OutboundConfig;
+use crate::session_backend::SessionBackend;
+use crate::ws_session::HeartbeatConfig;
+
+pub const USAGE: &str = "usage: bftt_server [options]
+
+options:
+  -c, --config <path>          TOML config file, also CONFIG_FILE
+      --bind <addr>            address to listen on, also BIND_ADDR
+      --redis <addr>           redis address, also REDIS_ADDR
+      --session-backend <name> redis, cookie or memory, also SESSION_BACKEND
+      --users-file <path>      JSON lines file users are logged to, also USERS_FILE
+      --log <filter>           env_logger filter, also RUST_LOG
+      --set <key>=<value>      set any config key, e.g. --set heartbeat.interval_secs=10
+  -h, --help                   print this help
+
+any config key can also be set with a BFTT_ env var, tables separated by a double
+underscore, e.g. BFTT_GAME__TURN_TIME_SECS=30
+
+precedence from lowest to highest: defaults, config file, env vars, flags";
+
+/// env vars that predate the config file and the key each overrides
+const ENV_KEYS: &[(&str, &str)] = &[
+    ("BIND_ADDR", "bind"),
+    ("REDIS_ADDR", "session.redis_addr"),
+    ("SESSION_BACKEND", "session.backend"),
+    ("USERS_FILE", "users_file"),
+    ("RUST_LOG", "log"),
+];
+/// prefix of env vars that set any config key
+const ENV_PREFIX: &str = "BFTT_";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "SessionConfig::new", deny_unknown_fields)]
+pub struct SessionConfig {
+    /// `redis`, `cookie` or `memory`
+    pub backend: String,
+    pub redis_addr: String,
+    /// only send the session cookie over HTTPS, turn off to serve over plain HTTP
+    pub secure_cookie: bool,
+}
+
+impl SessionConfig {
+    pub fn new() -> SessionConfig {
+        SessionConfig {
+            backend: "redis".into(),
+            redis_addr: "127.0.0.1:6379".into(),
+            secure_cookie: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "CorsConfig::new", deny_unknown_fields)]
+pub struct CorsConfig {
+    /// exact origins allowed to make cross origin requests, requests from other origins are rejected
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    /// allow cookies and authorization headers on cross origin requests
+    pub supports_credentials: bool,
+    /// seconds browsers may cache a preflight response for
+    pub max_age_secs: Option<usize>,
+}
+
+impl CorsConfig {
+    pub fn new() -> CorsConfig {
+        CorsConfig {
+            allowed_origins: vec!["http://localhost:3000".into()],
+            allowed_methods: vec!["GET".into(), "POST".into()],
+            allowed_headers: vec![
+                "authorization".into(),
+                "accept".into(),
+                "content-type".into(),
+            ],
+            supports_credentials: true,
+            max_age_secs: Some(60 * 60),
+        }
+    }
+}
+
+/// server configuration, missing keys take their default from `Config::new`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default = "Config::new", deny_unknown_fields)]
+pub struct Config {
+    /// address the HTTP server listens on
+    pub bind: String,
+    /// separate address `/metrics` is served on, kept off the public bind, not served if unset
+    pub metrics_bind: Option<String>,
+    /// env_logger filter
+    pub log: String,
+    /// JSON lines file users are logged to, users are kept in memory if unset
+    pub users_file: Option<String>,
+    /// board size of newly hosted games
+    pub board_size: u16,
+    pub session: SessionConfig,
+    pub cors: CorsConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub protocol: ProtocolConfig,
+    /// per session limits on messages waiting to be sent
+    pub outbound: OutboundConfig,
+    /// per session command budgets
+    pub rate_limit: RateLimitConfig,
+    /// defaults of newly hosted games
+    pub game: GameConfig,
+    pub matchmaking: MatchmakingConfig,
+    pub password: PasswordConfig,
+    pub login_guard: LoginGuardConfig,
+}
+
+/// parsed command line
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    pub help: bool,
+    pub config_file: Option<String>,
+    /// `(key, value)` overrides in the order given
+    pub overrides: Vec<(String, Value)>,
+}
+
+impl Args {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let key = match arg.as_str() {
+                "-h" | "--help" => {
+                    parsed.help = true;
+                    continue;
+                }
+                "-c" | "--config" => None,
+                "--bind" => Some("bind"),
+                "--redis" => Some("session.redis_addr"),
+                "--session-backend" => Some("session.backend"),
+                "--users-file" => Some("users_file"),
+                "--log" => Some("log"),
+                "--set" => Some(""),
+                _ => return Err(format!("unknown argument {}", arg)),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} requires a value", arg))?;
+            match key {
+                None => parsed.config_file = Some(value),
+                Some("") => {
+                    let (key, value) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("--set {} is not <key>=<value>", value))?;
+                    parsed.overrides.push((key.into(), parse_value(value)));
+                }
+                Some(key) => parsed.overrides.push((key.into(), Value::String(value))),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            bind: "0.0.0.0:8080".into(),
+            metrics_bind: None,
+            log: "actix_web=info,actix_redis=info".into(),
+            users_file: None,
+            board_size: BOARD_SIZE,
+            session: SessionConfig::new(),
+            cors: CorsConfig::new(),
+            heartbeat: HeartbeatConfig::new(),
+            protocol: ProtocolConfig::new(),
+            outbound: OutboundConfig::new(),
+            rate_limit: RateLimitConfig::new(),
+            game: GameConfig::new(),
+            matchmaking: MatchmakingConfig::new(),
+            password: PasswordConfig::new(),
+            login_guard: LoginGuardConfig::new(),
+        }
+    }
+
+    /// load config from file, env vars and command line, in increasing precedence, then validate
+    pub fn load<E>(args: Args, env: E) -> Result<Config, String>
+    where
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let env: Vec<(String, String)> = env.into_iter().collect();
+        let config_file = args.config_file.clone().or_else(|| {
+            env.iter()
+                .find(|(k, _)| k == "CONFIG_FILE")
+                .map(|(_, v)| v.clone())
+        });
+        let mut root = match config_file {
+            Some(path) => {
+                let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+                text.parse::<Value>()
+                    .map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => Value::Table(Default::default()),
+        };
+
+        let mut overrides = vec![];
+        for (name, value) in &env {
+            if let Some((_, key)) = ENV_KEYS.iter().find(|(n, _)| n == name) {
+                overrides.push((name.clone(), key.to_string(), Value::String(value.clone())));
+            } else if let Some(path) = name.strip_prefix(ENV_PREFIX) {
+                let key = path.to_lowercase().replace("__", ".");
+                overrides.push((name.clone(), key, parse_value(value)));
+            }
+        }
+        for (key, value) in args.overrides {
+            overrides.push((format!("--set {}", key), key, value));
+        }
+        for (source, key, value) in overrides {
+            set_key(&mut root, &key, value).map_err(|e| format!("{}: {}", source, e))?;
+        }
+
+        let config: Config = root.try_into().map_err(|e| format!("config: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// check values are usable before the server starts
+    pub fn validate(&self) -> Result<(), String> {
+        self.bind
+            .to_socket_addrs()
+            .map_err(|e| format!("bind {}: {}", self.bind, e))?;
+        if let Some(metrics_bind) = &self.metrics_bind {
+            metrics_bind
+                .to_socket_addrs()
+                .map_err(|e| format!("metrics_bind {}: {}", metrics_bind, e))?;
+            if *metrics_bind == self.bind {
+                return Err("metrics_bind must differ from bind".into());
+            }
+        }
+        self.session_backend()
+            .map_err(|e| format!("session: {}", e))?;
+        for origin in &self.cors.allowed_origins {
+            if !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(format!(
+                    "cors: origin {} must start with http:// or https://",
+                    origin
+                ));
+            }
+        }
+        for method in &self.cors.allowed_methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| format!("cors: invalid method {}", method))?;
+        }
+        for name in &self.cors.allowed_headers {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("cors: invalid header {}", name))?;
+        }
+        if self.heartbeat.interval_secs == 0 {
+            return Err("heartbeat: interval_secs must be at least 1".into());
+        }
+        if self.heartbeat.client_timeout_secs <= self.heartbeat.interval_secs {
+            return Err("heartbeat: client_timeout_secs must be greater than interval_secs".into());
+        }
+        self.protocol
+            .validate()
+            .map_err(|e| format!("protocol: {}", e))?;
+        self.outbound
+            .validate()
+            .map_err(|e| format!("outbound: {}", e))?;
+        self.rate_limit
+            .validate()
+            .map_err(|e| format!("rate_limit: {}", e))?;
+        self.game
+            .validate(self.board_size)
+            .map_err(|e| format!("game: {}", e))?;
+        self.matchmaking
+            .validate(self.board_size)
+            .map_err(|e| format!("matchmaking: {}", e))?;
+        crate::password::PasswordHasher::new(self.password.clone())
+            .map_err(|e| format!("password: {}", e))?;
+        Ok(())
+    }
+
+    pub fn session_backend(&self) -> Result<SessionBackend, String> {
+        SessionBackend::parse(&self.session.backend, &self.session.redis_addr)
+    }
+}
+
+/// parse `value` as a TOML value, falling back to a string so strings needn't be quoted
+fn parse_value(value: &str) -> Value {
+    format!("v = {}", value)
+        .parse::<Value>()
+        .ok()
+        .and_then(|mut t| t.as_table_mut().and_then(|t| t.remove("v")))
+        .unwrap_or_else(|| Value::String(value.to_owned()))
+}
+
+/// set dotted `key` in `root`, creating tables as needed
+fn set_key(root: &mut Value, key: &str, value: Value) -> Result<(), String> {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().filter(|p| !p.is_empty());
+    let last = last.ok_or_else(|| format!("invalid key {}", key))?;
+    let mut table = root;
+    for part in parts {
+        table = table
+            .as_table_mut()
+            .ok_or_else(|| format!("{} is not a table", key))?
+            .entry(part)
+            .or_insert_with(|| Value::Table(Default::default()));
+    }
+    table
+        .as_table_mut()
+        .ok_or_else(|| format!("{} is not a table", key))?
+        .insert(last.to_owned(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|a| a.to_string())).unwrap()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_precedence() -> Result<(), String> {
+        let path = std::env::temp_dir().join(format!("bftt_config_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "bind = \"127.0.0.1:9000\"\nboard_size = 12\n[game]\nturn_time_secs = 30\n",
+        )
+        .map_err(|e| e.to_string())?;
+        let path = path.to_string_lossy().to_string();
+
+        let config = Config::load(args(&["-c", &path]), env(&[]))?;
+        assert_eq!(config.bind, "127.0.0.1:9000");
+        assert_eq!(config.board_size, 12);
+        assert_eq!(config.game.turn_time_secs, 30);
+        // unset keys keep their defaults
+        assert_eq!(config.game.max_players, GameConfig::new().max_players);
+        assert_eq!(config.heartbeat, HeartbeatConfig::new());
+        // metrics stay off unless given their own bind
+        assert_eq!(config.metrics_bind, None);
+
+        let config = Config::load(
+            args(&["--bind", "127.0.0.1:9002", "--set", "game.init_lives=5"]),
+            env(&[
+                ("CONFIG_FILE", &path),
+                ("BIND_ADDR", "127.0.0.1:9001"),
+                ("BFTT_GAME__TURN_TIME_SECS", "40"),
+                ("BFTT_SESSION__BACKEND", "memory"),
+            ]),
+        )?;
+        assert_eq!(config.bind, "127.0.0.1:9002");
+        assert_eq!(config.game.turn_time_secs, 40);
+        assert_eq!(config.game.init_lives, 5);
+        assert_eq!(config.session_backend()?, SessionBackend::Memory);
+
+        fs::remove_file(&path).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        let load = |vars: &[(&str, &str)]| Config::load(Args::default(), env(vars));
+        assert!(load(&[]).is_ok());
+        assert!(load(&[("BFTT_NOPE", "1")]).is_err());
+        assert!(load(&[("BFTT_METRICS_BIND", "nowhere")]).is_err());
+        assert!(load(&[
+            ("BFTT_METRICS_BIND", "127.0.0.1:8080"),
+            ("BIND_ADDR", "127.0.0.1:8080")
+        ])
+        .is_err());
+        assert!(load(&[("BFTT_METRICS_BIND", "127.0.0.1:9100")]).is_ok());
+        assert!(load(&[("BFTT_BOARD_SIZE", "big")]).is_err());
+        assert!(load(&[("SESSION_BACKEND", "disk")]).is_err());
+        assert!(load(&[("BFTT_HEARTBEAT__CLIENT_TIMEOUT_SECS", "5")]).is_err());
+        assert!(load(&[("BFTT_PROTOCOL__MIN_VERSION", "3")]).is_err());
+        assert!(load(&[("BFTT_OUTBOUND__QUEUE_LEN", "0")]).is_err());
+        assert!(load(&[("BFTT_OUTBOUND__ON_OVERFLOW", "block")]).is_err());
+        assert!(load(&[("BFTT_RATE_LIMIT__ACTION__BURST", "0")]).is_err());
+        assert!(load(&[("BFTT_GAME__TURN_TIME_SECS", "1")]).is_err());
+        assert!(load(&[("BFTT_BOARD_SIZE", "3")]).is_err());
+        assert!(load(&[("BFTT_MATCHMAKING__PLAYERS", "3")]).is_err());
+        assert!(load(&[("BFTT_CORS__ALLOWED_ORIGINS", "[\"example.com\"]")]).is_err());
+        assert!(load(&[("BFTT_CORS__ALLOWED_METHODS", "[\"GE T\"]")]).is_err());
+        assert!(load(&[("BFTT_CORS__ALLOWED_HEADERS", "[\"x:y\"]")]).is_err());
+        assert!(Args::parse(vec!["--bind".to_string()]).is_err());
+        assert!(Args::parse(vec!["--what".to_string()]).is_err());
+    }
+}
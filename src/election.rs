@@ -1,6 +1,8 @@
+use serde::Serialize;
 use std::{
     cmp::Ordering,
     collections::{BTreeSet, HashMap, HashSet},
+    fmt::{Display, Formatter},
 };
 
 use crate::ovec;
@@ -11,6 +13,54 @@ fn hashset(v: Vec<String>) -> HashSet<String> {
     HashSet::from_iter(v)
 }
 
+/// reason a vote or tally was rejected, serialized as `{code, details}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
+pub enum ElectionError {
+    ElectionClosed {
+        election: String,
+    },
+    NotVoter {
+        voter_id: String,
+        election: String,
+    },
+    NotCandidate {
+        candidate_id: String,
+        election: String,
+    },
+    /// ballot must rank between 1 and `max` candidates
+    BadPreferences {
+        count: usize,
+        max: usize,
+    },
+    DuplicatePreferences,
+    /// tally attempted without any ballots
+    NoBallots,
+    /// vote count and ballots disagree
+    TallyDesync,
+}
+
+impl Display for ElectionError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ElectionError::ElectionClosed { election } => {
+                write!(f, "{} is closed to new votes", election)
+            }
+            ElectionError::NotVoter { voter_id, election } => {
+                write!(f, "{} is not a voter in {}", voter_id, election)
+            }
+            ElectionError::NotCandidate {
+                candidate_id,
+                election,
+            } => write!(f, "{} is not a candidate in {}", candidate_id, election),
+            ElectionError::BadPreferences { .. } => write!(f, "bad ballot preferences"),
+            ElectionError::DuplicatePreferences => write!(f, "ballot contains duplicates"),
+            ElectionError::NoBallots => write!(f, "max length of preferences unavailable"),
+            ElectionError::TallyDesync => write!(f, "vote count desynchronized"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Hash, Eq)]
 /// preferential voting ballet where votes are ordered from 1st pref in 0th entry onwards
 pub struct PrefBallot {
@@ -91,29 +141,37 @@ impl Election {
         self.voters = voters;
     }
 
-    pub fn check_open(&self) -> Result<(), String> {
+    pub fn check_open(&self) -> Result<(), ElectionError> {
         if !self.open {
-            return Err(format!("{} is closed to new votes", self.name).to_owned());
+            return Err(ElectionError::ElectionClosed {
+                election: self.name.clone(),
+            });
         }
         Ok(())
     }
 
-    pub fn check_voter_id(&self, voter_id: &str) -> Result<(), String> {
+    pub fn check_voter_id(&self, voter_id: &str) -> Result<(), ElectionError> {
         if !self.voters.contains(voter_id) {
-            return Err(format!("{} is not a voter in {}", voter_id, self.name).into());
+            return Err(ElectionError::NotVoter {
+                voter_id: voter_id.into(),
+                election: self.name.clone(),
+            });
         }
         Ok(())
     }
 
-    pub fn check_candidate_id(&self, candidate_id: &str) -> Result<(), String> {
+    pub fn check_candidate_id(&self, candidate_id: &str) -> Result<(), ElectionError> {
         if !self.candidates.contains(candidate_id) {
-            return Err(format!("{} is not a candidate in {}", candidate_id, self.name).into());
+            return Err(ElectionError::NotCandidate {
+                candidate_id: candidate_id.into(),
+                election: self.name.clone(),
+            });
         }
         Ok(())
     }
 
     /// remove ballot of voter from vote_count and voter_ballot
-    pub fn remove_ballot(&mut self, voter_id: &str) -> Result<(), String> {
+    pub fn remove_ballot(&mut self, voter_id: &str) -> Result<(), ElectionError> {
         self.check_open()?;
         self.check_voter_id(voter_id)?;
         // remove ballot from candidate's vote tally
@@ -132,13 +190,16 @@ impl Election {
         Ok(())
     }
 
-    pub fn vote(&mut self, voter_id: &str, prefs: Vec<String>) -> Result<(), String> {
+    pub fn vote(&mut self, voter_id: &str, prefs: Vec<String>) -> Result<(), ElectionError> {
         // <VALIDATE>
         // remove voter's old ballot if it exists (indirectly checks if open, if voter id exists)
         self.remove_ballot(voter_id)?;
         // check prefs length
         if &prefs.len() < &1 || &prefs.len() > &self.candidates.len() {
-            return Err("bad ballot preferences".into());
+            return Err(ElectionError::BadPreferences {
+                count: prefs.len(),
+                max: self.candidates.len(),
+            });
         }
         // candidates must be in candidates
         for candidate_id in &prefs {
@@ -147,7 +208,7 @@ impl Election {
         // no repeats allowed
         let dupes: HashSet<String> = hashset(prefs.clone());
         if dupes.len() != prefs.len() {
-            return Err("ballot contains duplicates".into());
+            return Err(ElectionError::DuplicatePreferences);
         }
 
         // <EXECUTE>
@@ -177,7 +238,7 @@ impl Election {
     }
 
     /// remove voter from voters and add to candidates
-    pub fn move_voter_to_candidate(&mut self, voter_id: &str) -> Result<(), String> {
+    pub fn move_voter_to_candidate(&mut self, voter_id: &str) -> Result<(), ElectionError> {
         self.check_voter_id(voter_id)?;
         self.remove_ballot(voter_id)?;
         self.voters.remove(voter_id);
@@ -187,7 +248,7 @@ impl Election {
     }
 
     /// remove candidate from candidates and add to voters
-    pub fn move_candidate_to_voter(&mut self, candidate_id: &str) -> Result<(), String> {
+    pub fn move_candidate_to_voter(&mut self, candidate_id: &str) -> Result<(), ElectionError> {
         self.check_candidate_id(candidate_id)?;
         self.check_open()?;
         self.candidates.remove(candidate_id);
@@ -212,6 +273,24 @@ impl Election {
             .and_then(|f| f.ballot.prefs.iter().next().cloned())
     }
 
+    /// get a voter's ranked preferences, empty if they haven't voted
+    pub fn get_voter_prefs(&self, voter_id: &str) -> Vec<String> {
+        self.voter_ballots
+            .get(voter_id)
+            .map(|f| f.ballot.prefs.clone())
+            .unwrap_or_default()
+    }
+
+    /// close the election and get its winners
+    /// preferences are only counted if a ballot ranks more than one candidate,
+    /// single choice elections are decided by first preferences alone
+    pub fn tally(&mut self) -> Result<HashSet<String>, ElectionError> {
+        if self.ballots_ordered.iter().any(|b| b.prefs.len() > 1) {
+            self.apply_preferential_voting()?;
+        }
+        Ok(self.get_winners())
+    }
+
     /// get the candidates with the highest number votes (can be more than 1 candidate with most votes)
     pub fn get_winners(&mut self) -> HashSet<String> {
         // candidates must have at least 1 vote, candidates with empty hashsets are ignored
@@ -231,7 +310,7 @@ impl Election {
 
     /// apply preferential voting candidate votes https://web.archive.org/web/20210313023849/https://aec.gov.au/learn/files/poster-counting-hor-pref-voting.pdf
     /// apply optional based preferential voting process to vote_count
-    pub fn apply_preferential_voting(&mut self) -> Result<(), String> {
+    pub fn apply_preferential_voting(&mut self) -> Result<(), ElectionError> {
         self.open = false;
         self.init_vote_count = Some(self.vote_count.clone());
         let half = self.voter_ballots.len() / 2;
@@ -241,7 +320,7 @@ impl Election {
             .ballots_ordered
             .iter()
             .next_back()
-            .ok_or("max length of preferences unavailable")?
+            .ok_or(ElectionError::NoBallots)?
             .prefs
             .len();
         while processing > -1 && processing < 10000 {
@@ -254,7 +333,9 @@ impl Election {
                     max = (votes.len(), ovec![id]);
                 } else if votes.len() == max.0 {
                     max.1.push(id.clone());
-                } else if votes.len() > 0 && votes.len() < min.0 {
+                }
+                // checked apart from the max so the order candidates are visited in doesn't matter
+                if votes.len() > 0 && votes.len() < min.0 {
                     min = (votes.len(), id.clone());
                 }
             }
@@ -268,7 +349,7 @@ impl Election {
                 let min_ballots = self
                     .vote_count
                     .insert(min.1, HashSet::new())
-                    .ok_or("min voted candidate not found in vote count?".to_owned())?;
+                    .ok_or(ElectionError::TallyDesync)?;
                 // move votes from min candidate into ballots next preferences
                 for mut ballot in min_ballots {
                     if ballot.ballot.prefs.len() > pref {
@@ -280,8 +361,10 @@ impl Election {
                         ballot.allocated = vote.to_owned();
                         self.voter_ballots
                             .insert(ballot.ballot.voter.clone(), ballot.clone());
-                        let candidate =
-                            self.vote_count.get_mut(vote).ok_or("vote_count vanished")?;
+                        let candidate = self
+                            .vote_count
+                            .get_mut(vote)
+                            .ok_or(ElectionError::TallyDesync)?;
                         candidate.insert(ballot);
                     }
                 }
@@ -299,6 +382,7 @@ impl Election {
         self.vote_count = HashMap::new();
         self.init_vote_count = None;
         self.voter_ballots = HashMap::new();
+        self.ballots_ordered = BTreeSet::new();
     }
 }
 
@@ -312,7 +396,7 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_curse_election() -> Result<(), String> {
+    fn test_curse_election() -> Result<(), ElectionError> {
         let mut el = Election::new("test");
         assert_eq!(el.candidates.len(), 0);
         assert_eq!(el.voters.len(), 0);
@@ -331,7 +415,10 @@ mod tests {
         // voting does not work
         assert_eq!(
             el.vote("a", ovec!["b"]),
-            Err("a is not a voter in test".into())
+            Err(ElectionError::NotVoter {
+                voter_id: "a".into(),
+                election: "test".into()
+            })
         );
 
         // no ballot
@@ -340,7 +427,10 @@ mod tests {
         // removing ballot fails
         assert_eq!(
             el.remove_ballot("a"),
-            Err("a is not a voter in test".into())
+            Err(ElectionError::NotVoter {
+                voter_id: "a".into(),
+                election: "test".into()
+            })
         );
 
         // no votes means no one wins
@@ -350,7 +440,10 @@ mod tests {
         // moving voter to candidate fails
         assert_eq!(
             el.move_voter_to_candidate("a"),
-            Err("a is not a voter in test".into())
+            Err(ElectionError::NotVoter {
+                voter_id: "a".into(),
+                election: "test".into()
+            })
         );
 
         // converting candidate to voter works
@@ -368,12 +461,15 @@ mod tests {
         el.move_candidate_to_voter("b")?;
         assert_eq!(
             el.vote("b", ovec!["xyz"]),
-            Err("xyz is not a candidate in test".into())
+            Err(ElectionError::NotCandidate {
+                candidate_id: "xyz".into(),
+                election: "test".into()
+            })
         );
         // empty vote fails
         assert_eq!(
             el.vote("b", Vec::new()),
-            Err("bad ballot preferences".into())
+            Err(ElectionError::BadPreferences { count: 0, max: 7 })
         );
 
         // there is no vote_count or voter_ballot
@@ -446,7 +542,7 @@ mod tests {
     }
 
     #[test]
-    fn test_preferential_voting_basic() -> Result<(), String> {
+    fn test_preferential_voting_basic() -> Result<(), ElectionError> {
         let mut el = Election::new("test");
 
         // setting candidates, voters works
@@ -461,7 +557,12 @@ mod tests {
 
         // voting after applying preferential voting fails
         let e = el.vote("a", ovec!["a"]);
-        assert_eq!(e, Err("test is closed to new votes".into()));
+        assert_eq!(
+            e,
+            Err(ElectionError::ElectionClosed {
+                election: "test".into()
+            })
+        );
 
         assert_eq!(el.get_winners(), hashset(ovec!["a"]));
 
@@ -474,7 +575,7 @@ mod tests {
 
         // cannot have duplicates
         let e = el.vote("d", ovec!["a", "a"]);
-        assert_eq!(e, Err("ballot contains duplicates".into()));
+        assert_eq!(e, Err(ElectionError::DuplicatePreferences));
 
         el.vote("d", ovec!["b"])?;
 
@@ -485,7 +586,7 @@ mod tests {
     }
 
     #[test]
-    fn test_preferential_voting_random() -> Result<(), String> {
+    fn test_preferential_voting_random() -> Result<(), ElectionError> {
         let mut el = Election::new("test");
         let cand = ovec!["a", "b", "c"];
         let voters = ovec!["a", "b", "c", "d"];
@@ -514,7 +615,7 @@ mod tests {
     }
 
     #[test]
-    fn test_preferential_voting_removing_ballots() -> Result<(), String> {
+    fn test_preferential_voting_removing_ballots() -> Result<(), ElectionError> {
         let mut el = Election::new("test");
         let cand = ovec!["a", "b", "c"];
         let voters = ovec!["a", "b", "c", "d", "e"];
@@ -566,7 +667,36 @@ mod tests {
     }
 
     #[test]
-    fn test_orphaned_ballots_preferential() -> Result<(), String> {
+    fn test_tally() -> Result<(), ElectionError> {
+        let mut el = Election::new("test");
+        el.set_candidates(hashset(ovec!["a", "b", "c"]));
+        el.set_voters(hashset(ovec!["v", "w", "x", "y", "z"]));
+        // single choice ballots are counted by first preference, ties all win
+        for (voter, candidate) in [("v", "a"), ("w", "a"), ("x", "b"), ("y", "b"), ("z", "c")] {
+            el.vote(voter, ovec![candidate])?;
+        }
+        assert_eq!(el.tally()?, hashset(ovec!["a", "b"]));
+        assert_eq!(el.init_vote_count, None);
+
+        // c is eliminated and z's vote moves to a
+        el.reset();
+        for (voter, candidate) in [("v", "a"), ("w", "a"), ("x", "b"), ("y", "b")] {
+            el.vote(voter, ovec![candidate])?;
+        }
+        el.vote("z", ovec!["c", "a"])?;
+        assert_eq!(el.get_voter_prefs("z"), vec!["c", "a"]);
+        assert_eq!(el.tally()?, hashset(ovec!["a"]));
+
+        // reset forgets ranked ballots
+        el.reset();
+        el.vote("z", ovec!["c"])?;
+        assert_eq!(el.tally()?, hashset(ovec!["c"]));
+        assert_eq!(el.init_vote_count, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_orphaned_ballots_preferential() -> Result<(), ElectionError> {
         let mut el = Election::new("test");
         let cands = ovec!["a", "b", "c", "d", "_"];
         // _ cand illustrates preference never used
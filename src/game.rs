@@ -1,12 +1,14 @@
 use rand::distributions::Uniform;
 use rand::prelude::{Distribution, ThreadRng};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::common::{ConfigGameOp, InitPosConfig};
-use crate::election::Election;
+use crate::common::{ConfigGameOp, InitPosConfig, Visibility};
+use crate::election::{Election, ElectionError};
+use crate::patch::{self, PatchOp};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Pos {
@@ -33,6 +35,157 @@ impl Display for Pos {
     }
 }
 
+/// reason a game rejected a player or config change, serialized as `{code, details}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
+pub enum GameError {
+    PlayerNotFound {
+        user_id: String,
+    },
+    PlayerDead {
+        user_id: String,
+    },
+    PlayerAlive {
+        user_id: String,
+    },
+    InsufficientActionPoints {
+        required: u32,
+        available: u32,
+    },
+    /// action's point cost or lives effect estimate doesn't match the server's
+    WrongCost {
+        required: u32,
+        requested: u32,
+    },
+    OutOfRange {
+        from: Pos,
+        to: Pos,
+        range: usize,
+    },
+    OutOfBounds {
+        pos: Pos,
+        size: usize,
+    },
+    SpaceOccupied {
+        pos: Pos,
+    },
+    /// player tried to target themselves with `action`
+    SelfTarget {
+        action: String,
+    },
+    NotInPosition {
+        pos: Pos,
+    },
+    NoHearts {
+        pos: Pos,
+    },
+    ManualPositionDisabled,
+    /// player's position is missing from the board
+    PlayerDesynchronized {
+        user_id: String,
+    },
+    GameFull {
+        max_players: u16,
+    },
+    NotJoinable,
+    NotEnoughPlayers {
+        required: usize,
+        joined: usize,
+    },
+    /// change is only allowed before the game starts
+    AlreadyStarted,
+    NotInProgress,
+    GameOver,
+    TurnTimeOutOfRange {
+        turn_time_secs: u64,
+        min_secs: u64,
+        max_secs: u64,
+    },
+    PlayersWontFit {
+        max_players: u16,
+        board_size: usize,
+    },
+    /// board has no squares
+    EmptyBoard,
+    MaxPlayersBelowPlayerCount {
+        max_players: u16,
+        player_count: usize,
+    },
+    /// game is `Password` visible and the password is missing or wrong
+    WrongPassword,
+    /// `Password` visibility without a password set
+    NoPassword,
+    /// game is `Private`, joining takes an invite code
+    InviteRequired,
+    #[serde(untagged)]
+    Election(ElectionError),
+}
+
+impl Display for GameError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            GameError::PlayerNotFound { user_id } => write!(f, "player {} not found", user_id),
+            GameError::PlayerDead { user_id } => write!(f, "{} has no life", user_id),
+            GameError::PlayerAlive { user_id } => write!(f, "{} is alive", user_id),
+            GameError::InsufficientActionPoints { required, .. } => {
+                write!(f, "{} action points required", required)
+            }
+            GameError::WrongCost { required, .. } => write!(f, "action costs {}", required),
+            GameError::OutOfRange { .. } => write!(f, "move out of range"),
+            GameError::OutOfBounds { .. } => write!(f, "out of range"),
+            GameError::SpaceOccupied { .. } => write!(f, "space is occupied"),
+            GameError::SelfTarget { action } => match action.as_str() {
+                "attack" => write!(f, "Stop hurting yourself"),
+                "give" => write!(f, "this is a futile endeavour"),
+                _ => write!(f, "you can't {} yourself", action),
+            },
+            GameError::NotInPosition { .. } => write!(f, "player not in position"),
+            GameError::NoHearts { .. } => write!(f, "position heartless"),
+            GameError::ManualPositionDisabled => {
+                write!(f, "manual initial positioning must be enabled")
+            }
+            GameError::PlayerDesynchronized { .. } => write!(f, "player desynchronized"),
+            GameError::GameFull { .. } => write!(f, "game is at max capacity"),
+            GameError::NotJoinable => write!(f, "game cannot be joined"),
+            GameError::NotEnoughPlayers { required, .. } => {
+                write!(f, "{} or more players required to start a game", required)
+            }
+            GameError::AlreadyStarted => write!(f, "game already started"),
+            GameError::NotInProgress => write!(f, "Game not in progress"),
+            GameError::GameOver => write!(f, "game over"),
+            GameError::TurnTimeOutOfRange {
+                min_secs, max_secs, ..
+            } => write!(
+                f,
+                "turn time must be between {} and {} seconds",
+                min_secs, max_secs
+            ),
+            GameError::PlayersWontFit {
+                max_players,
+                board_size,
+            } => write!(
+                f,
+                "{} players won't fit in a {} by {} board",
+                max_players, board_size, board_size
+            ),
+            GameError::EmptyBoard => write!(f, "board size must be at least 1"),
+            GameError::MaxPlayersBelowPlayerCount { .. } => {
+                write!(f, "Cannot set max players below current player count")
+            }
+            GameError::WrongPassword => write!(f, "wrong game password"),
+            GameError::NoPassword => write!(f, "game password must be set"),
+            GameError::InviteRequired => write!(f, "game is invite only"),
+            GameError::Election(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ElectionError> for GameError {
+    fn from(e: ElectionError) -> GameError {
+        GameError::Election(e)
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Player {
     pub user_id: String,
@@ -59,23 +212,30 @@ impl Player {
         }
     }
 
-    pub fn is_alive(&self) -> Result<(), String> {
+    pub fn is_alive(&self) -> Result<(), GameError> {
         if self.lives < 1 {
-            return Err(format!("{} has no life", self.user_id));
+            return Err(GameError::PlayerDead {
+                user_id: self.user_id.clone(),
+            });
         }
         Ok(())
     }
 
-    pub fn is_dead(&self) -> Result<(), String> {
+    pub fn is_dead(&self) -> Result<(), GameError> {
         if self.lives > 0 {
-            return Err(format!("{} is alive", self.user_id));
+            return Err(GameError::PlayerAlive {
+                user_id: self.user_id.clone(),
+            });
         }
         Ok(())
     }
 
-    pub fn has_action_points(&self, required: u32) -> Result<(), String> {
+    pub fn has_action_points(&self, required: u32) -> Result<(), GameError> {
         if self.action_points < required {
-            return Err("insufficient action points".into());
+            return Err(GameError::InsufficientActionPoints {
+                required,
+                available: self.action_points,
+            });
         }
         Ok(())
     }
@@ -83,11 +243,15 @@ impl Player {
     /// validate action points
     /// validate player range ability
     /// validate range ability against move distance
-    pub fn moveable_in_prog(&self, pos: &Pos) -> Result<(), String> {
+    pub fn moveable_in_prog(&self, pos: &Pos) -> Result<(), GameError> {
         self.has_action_points(1)?;
         let dist = Pos::xy_distances(&self.pos, pos);
         if dist.x > self.range || dist.y > self.range {
-            return Err("move out of range".into());
+            return Err(GameError::OutOfRange {
+                from: self.pos.clone(),
+                to: pos.clone(),
+                range: self.range,
+            });
         }
         Ok(())
     }
@@ -107,13 +271,16 @@ impl<T> Board<T> {
         }
     }
 
-    pub fn in_bounds(&mut self, pos: &Pos, check_occupied: bool) -> Result<(), String> {
+    pub fn in_bounds(&mut self, pos: &Pos, check_occupied: bool) -> Result<(), GameError> {
         let Pos { x, y } = pos;
         if x >= &self.size || y >= &self.size {
-            return Err("out of range".into());
+            return Err(GameError::OutOfBounds {
+                pos: pos.clone(),
+                size: self.size,
+            });
         }
         if check_occupied && self.map.get(&pos.key()).is_some() {
-            return Err("space is occupied".into());
+            return Err(GameError::SpaceOccupied { pos: pos.clone() });
         }
         Ok(())
     }
@@ -148,14 +315,16 @@ impl PlayersAliveDead {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum GamePhase {
     Init,
     InProg,
     End,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// missing fields in a deserialized config take their default from `GameConfig::new`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default = "GameConfig::new", deny_unknown_fields)]
 pub struct GameConfig {
     pub turn_time_secs: u64,
     pub max_players: u16,
@@ -163,6 +332,7 @@ pub struct GameConfig {
     pub init_lives: u32,
     pub init_range: usize,
     pub init_pos: InitPosConfig,
+    pub visibility: Visibility,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -176,10 +346,88 @@ pub struct Game {
     pub board_hearts: Board<u32>,
     pub turn_end_unix: u64,
     pub config: GameConfig,
+    /// incremented on every change to the game, clients compare it to detect missed updates
+    pub version: u64,
+    /// public game as of the last update sent to players, changes are diffed against it
+    #[serde(skip_serializing)]
+    published: Value,
+    #[serde(skip_serializing)]
+    published_version: u64,
     #[serde(skip_serializing)]
     rnd: ThreadRng,
     #[serde(skip_serializing)]
     pub curse_election: Election,
+    /// hash of the password required to join while the game is `Password` visible
+    #[serde(skip_serializing)]
+    password_hash: Option<String>,
+}
+
+/// a player's view of the game, the public game plus the player's private data
+#[derive(Debug, Serialize)]
+pub struct PlayerGameState {
+    /// public game, including its version and turn end
+    /// serialized as `Game` holds a thread local RNG and can't be sent between threads
+    pub game: Value,
+    /// checksum of `game`, as sent with game updates
+    pub checksum: String,
+    pub action_points: u32,
+    /// player's current curse ballot
+    pub curse: PlayerResponse,
+}
+
+/// listing of a game for lobby browsers
+#[derive(Debug, Clone, Serialize)]
+pub struct GameSummary {
+    pub game_id: String,
+    pub phase: GamePhase,
+    pub host_user_id: Option<String>,
+    /// user IDs of the players, sorted
+    pub players: Vec<String>,
+    /// players that can still join, 0 once the game has started
+    pub open_seats: usize,
+    pub board_size: usize,
+    pub config: GameConfig,
+    pub version: u64,
+}
+
+/// lobby query, games must match every field given
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GameFilter {
+    pub phase: Option<GamePhase>,
+    pub min_open_seats: Option<usize>,
+    pub max_players: Option<u16>,
+    pub board_size: Option<usize>,
+    pub min_turn_time_secs: Option<u64>,
+    pub max_turn_time_secs: Option<u64>,
+}
+
+impl GameFilter {
+    pub fn matches(&self, game: &Game) -> bool {
+        self.phase.as_ref().is_none_or(|p| *p == game.phase)
+            && self.min_open_seats.is_none_or(|n| game.open_seats() >= n)
+            && self
+                .max_players
+                .is_none_or(|n| game.config.max_players == n)
+            && self.board_size.is_none_or(|n| game.board.size == n)
+            && self
+                .min_turn_time_secs
+                .is_none_or(|n| game.config.turn_time_secs >= n)
+            && self
+                .max_turn_time_secs
+                .is_none_or(|n| game.config.turn_time_secs <= n)
+    }
+}
+
+/// changes to the public game from `from_version` to `version`
+#[derive(Debug, Clone, Serialize)]
+pub struct GameUpdate {
+    pub game_id: String,
+    pub from_version: u64,
+    pub version: u64,
+    pub patch: Vec<PatchOp>,
+    /// checksum of the public game at `version`, a mismatch means the client should resync
+    pub checksum: String,
 }
 
 pub enum InsertPlayerResult {
@@ -216,6 +464,10 @@ pub struct ReviveAction {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct CurseAction {
     target_user_id: Option<String>,
+    /// players ranked after the target, their votes move down the ranking as players are
+    /// eliminated from the tally, see `protocol::PREFERENTIAL_VOTES`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    preferences: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -241,6 +493,13 @@ pub enum ActionType {
     Redeem(RedeemAction),
 }
 
+impl ActionType {
+    /// curse ranking more than one player
+    pub fn is_preferential(&self) -> bool {
+        matches!(self, ActionType::Curse(curse) if !curse.preferences.is_empty())
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct PlayerAction {
     pub user_id: String,
@@ -292,6 +551,9 @@ pub const ATTACK_LIVES_EFFECT: u32 = 1;
 pub const ATTACK_COST: u32 = 1;
 pub const RANGE_UPGRADE_COST: u32 = 3;
 pub const HEAL_COST: u32 = 3;
+pub const MIN_PLAYERS: usize = 4;
+pub const MIN_TURN_TIME_SECS: u64 = 10;
+pub const MAX_TURN_TIME_SECS: u64 = 60 * 60 * 24;
 
 impl GameConfig {
     pub fn new() -> GameConfig {
@@ -301,13 +563,26 @@ impl GameConfig {
             init_action_points: INIT_ACTION_POINTS,
             init_lives: INIT_LIVES,
             init_pos: InitPosConfig::Random,
+            visibility: Visibility::Unlisted,
             turn_time_secs: TURN_TIME_SECS,
         }
     }
+
+    /// check config is valid for a new game on a board of `size` by `size`
+    pub fn validate(&self, size: u16) -> Result<(), GameError> {
+        if size == 0 {
+            return Err(GameError::EmptyBoard);
+        }
+        let mut game = Game::new(String::new(), size, GameConfig::new(), rand::thread_rng());
+        game.configure(&ConfigGameOp::TurnTimeSecs(self.turn_time_secs))?;
+        game.configure(&ConfigGameOp::MaxPlayers(self.max_players))?;
+        game.configure(&ConfigGameOp::Visibility(self.visibility))?;
+        Ok(())
+    }
 }
 
 impl Game {
-    pub fn new(game_id: String, size: u16, rnd: ThreadRng) -> Game {
+    pub fn new(game_id: String, size: u16, config: GameConfig, rnd: ThreadRng) -> Game {
         Game {
             phase: GamePhase::Init,
             game_id,
@@ -317,25 +592,31 @@ impl Game {
             board: Board::new(size as usize),
             board_hearts: Board::new(size as usize),
             turn_end_unix: 0,
-            config: GameConfig::new(),
+            config,
+            version: 0,
+            published: Value::Null,
+            published_version: 0,
             rnd,
             curse_election: Election::new("cursings"),
+            password_hash: None,
         }
     }
 
-    pub fn set_host(&mut self, host_id: String) -> Result<(), String> {
+    pub fn set_host(&mut self, host_id: String) -> Result<(), GameError> {
         self.host_user_id = Some(host_id.clone());
         self.insert_player(host_id).map(|_| ())
     }
 
-    pub fn insert_player(&mut self, user_id: String) -> Result<InsertPlayerResult, String> {
+    pub fn insert_player(&mut self, user_id: String) -> Result<InsertPlayerResult, GameError> {
         if self.players.contains_key(&user_id) {
             return Ok(InsertPlayerResult::Rejoined);
         }
         if matches!(self.phase, GamePhase::Init) {
             // fail if game is full
             if self.players.len() == usize::from(self.config.max_players) {
-                return Err("game is at max capacity".into());
+                return Err(GameError::GameFull {
+                    max_players: self.config.max_players,
+                });
             }
             let mut player = Player::new(user_id.clone(), self.game_id.clone());
             // set loadout
@@ -354,9 +635,10 @@ impl Game {
             // insert player
             self.players.insert(user_id.clone(), player);
             self.players_alive_dead.set_alive(&user_id);
+            self.version += 1;
             return Ok(InsertPlayerResult::Joined);
         }
-        return Err("game cannot be joined".to_owned());
+        return Err(GameError::NotJoinable);
     }
 
     /// set player's position randomly
@@ -388,38 +670,45 @@ impl Game {
     pub fn configure(
         &mut self,
         conf: &ConfigGameOp,
-    ) -> Result<Option<HashMap<String, String>>, String> {
+    ) -> Result<Option<HashMap<String, String>>, GameError> {
         if !matches!(self.phase, GamePhase::Init) {
-            return Err("configuration must be during initialisation".into());
+            return Err(GameError::AlreadyStarted);
         }
         match conf.clone() {
             ConfigGameOp::TurnTimeSecs(v) => {
-                if v < 10 {
-                    return Err("minimum of 10 seconds is required".into());
-                }
-                if v > 60 * 60 * 24 {
-                    return Err("maximum of 24 hours is required".into());
+                if !(MIN_TURN_TIME_SECS..=MAX_TURN_TIME_SECS).contains(&v) {
+                    return Err(GameError::TurnTimeOutOfRange {
+                        turn_time_secs: v,
+                        min_secs: MIN_TURN_TIME_SECS,
+                        max_secs: MAX_TURN_TIME_SECS,
+                    });
                 }
                 self.config.turn_time_secs = v;
             }
             ConfigGameOp::MaxPlayers(v) => {
                 if self.board.size * self.board.size < v.into() {
-                    return Err(format!(
-                        "{} players won't fit in a {} by {} board",
-                        v, self.board.size, self.board.size,
-                    ));
+                    return Err(GameError::PlayersWontFit {
+                        max_players: v,
+                        board_size: self.board.size,
+                    });
                 }
                 if self.players.len() > v.into() {
-                    return Err("Cannot set max players below current player count".into());
+                    return Err(GameError::MaxPlayersBelowPlayerCount {
+                        max_players: v,
+                        player_count: self.players.len(),
+                    });
                 }
                 self.config.max_players = v;
             }
             ConfigGameOp::BoardSize(v) => {
+                if v == 0 {
+                    return Err(GameError::EmptyBoard);
+                }
                 if usize::from(self.config.max_players) > v * v {
-                    return Err(format!(
-                        "{} players won't fit in a {} by {} board",
-                        self.config.max_players, v, v,
-                    ));
+                    return Err(GameError::PlayersWontFit {
+                        max_players: self.config.max_players,
+                        board_size: v,
+                    });
                 }
                 self.board.size = v;
             }
@@ -454,20 +743,43 @@ impl Game {
                         }
                     }
                     if !res.is_empty() {
+                        self.version += 1;
                         return Ok(Some(res));
                     }
                 }
             }
+            ConfigGameOp::Visibility(v) => {
+                match v {
+                    Visibility::Password if self.password_hash.is_none() => {
+                        return Err(GameError::NoPassword)
+                    }
+                    Visibility::Password => (),
+                    _ => self.password_hash = None,
+                }
+                self.config.visibility = v;
+            }
+            ConfigGameOp::Password(v) => {
+                if v.is_empty() {
+                    return Err(GameError::NoPassword);
+                }
+                // the relay hashes the password before it gets here
+                self.password_hash = Some(v);
+                self.config.visibility = Visibility::Password;
+            }
         };
+        self.version += 1;
         Ok(None)
     }
 
-    pub fn start_game(&mut self) -> Result<(), String> {
+    pub fn start_game(&mut self) -> Result<(), GameError> {
         if !matches!(self.phase, GamePhase::Init) {
-            return Err("game already started".to_owned());
+            return Err(GameError::AlreadyStarted);
         }
-        if self.players.len() < 4 {
-            return Err("4 or more players required to start a game".to_owned());
+        if self.players.len() < MIN_PLAYERS {
+            return Err(GameError::NotEnoughPlayers {
+                required: MIN_PLAYERS,
+                joined: self.players.len(),
+            });
         }
         let die = self.board_die();
         for player in self.players.values_mut() {
@@ -479,6 +791,7 @@ impl Game {
             .set_candidates(self.players_alive_dead.alive.clone());
         self.phase = GamePhase::InProg;
         self.turn_end_unix = from_now(self.config.turn_time_secs);
+        self.version += 1;
         Ok(())
     }
 
@@ -488,9 +801,9 @@ impl Game {
     }
 
     /// error if game not in progress
-    pub fn check_in_prog(&self) -> Result<(), String> {
+    pub fn check_in_prog(&self) -> Result<(), GameError> {
         if !matches!(self.phase, GamePhase::InProg) {
-            return Err("Game not in progress".to_owned());
+            return Err(GameError::NotInProgress);
         }
         Ok(())
     }
@@ -524,6 +837,7 @@ impl Game {
                 Some(1)
             })
             .unwrap();
+        self.version += 1;
         (Pos { x, y }, v)
     }
 
@@ -531,7 +845,7 @@ impl Game {
     pub fn replenish(
         &mut self,
         cursed: &HashSet<String>,
-    ) -> Result<Vec<(String, String, u32)>, String> {
+    ) -> Result<Vec<(String, String, u32)>, GameError> {
         self.check_in_prog()?;
         let mut action_point_updates: Vec<(String, String, u32)> = Vec::new();
         for player in self.players.values_mut() {
@@ -547,23 +861,28 @@ impl Game {
             ));
         }
         self.turn_end_unix = from_now(self.config.turn_time_secs);
+        self.version += 1;
         Ok(action_point_updates)
     }
 
-    pub fn clone_player(&self, player_id: &str) -> Result<Player, String> {
+    pub fn clone_player(&self, player_id: &str) -> Result<Player, GameError> {
         let player = self
             .players
             .get(player_id)
-            .ok_or(format!("player {} not found", player_id))?
+            .ok_or_else(|| GameError::PlayerNotFound {
+                user_id: player_id.into(),
+            })?
             .clone();
         Ok(player)
     }
 
-    pub fn check_for_end_phase_move(&mut self, player_id: &str) -> Result<(), String> {
+    pub fn check_for_end_phase_move(&mut self, player_id: &str) -> Result<(), GameError> {
         if self
             .players
             .get(player_id)
-            .ok_or(format!("{} does not exist", player_id).to_string())?
+            .ok_or_else(|| GameError::PlayerNotFound {
+                user_id: player_id.into(),
+            })?
             .lives
             == 0
         {
@@ -583,9 +902,9 @@ impl Game {
         &mut self,
         user_id: &str,
         action: &ActionType,
-    ) -> Result<(PlayerResponse, PlayerActionResult), String> {
+    ) -> Result<(PlayerResponse, PlayerActionResult), GameError> {
         if matches!(self.phase, GamePhase::End) {
-            return Err("game over".into());
+            return Err(GameError::GameOver);
         }
         let mut action_point_updates: Vec<(String, String, u32)> = Vec::new();
         let mut players_alive_dead = None;
@@ -607,7 +926,7 @@ impl Game {
                     player_flux.action_points -= MOVE_COST;
                 } else if matches!(self.phase, GamePhase::Init) {
                     if !matches!(self.config.init_pos, InitPosConfig::Manual) {
-                        return Err("manual initial positioning must be enabled".into());
+                        return Err(GameError::ManualPositionDisabled);
                     }
                 }
                 // <EXECUTE>
@@ -616,7 +935,9 @@ impl Game {
                     self.board
                         .map
                         .remove(&player_flux.pos.key())
-                        .ok_or("player desynchronized")?;
+                        .ok_or_else(|| GameError::PlayerDesynchronized {
+                            user_id: user_id.into(),
+                        })?;
                 }
                 // set MoveActionEvent
                 let action_event = ActionTypeEvent::Move(MoveEvent {
@@ -636,7 +957,9 @@ impl Game {
                 self.check_in_prog()?;
                 // validate player is not targeting themselves
                 if user_id == attack.target_user_id {
-                    return Err("Stop hurting yourself".into());
+                    return Err(GameError::SelfTarget {
+                        action: "attack".into(),
+                    });
                 }
                 // validate player has lives
                 player_flux.is_alive()?;
@@ -649,7 +972,10 @@ impl Game {
                 player_flux.moveable_in_prog(&target_flux.pos)?;
                 // action's lives effect is -1
                 if attack.lives_effect != ATTACK_LIVES_EFFECT {
-                    return Err("attacking must take 1 life :'(".into());
+                    return Err(GameError::WrongCost {
+                        required: ATTACK_LIVES_EFFECT,
+                        requested: attack.lives_effect,
+                    });
                 }
                 // remove player action point
                 player_flux.action_points -= ATTACK_COST;
@@ -691,7 +1017,9 @@ impl Game {
                 self.check_in_prog()?;
                 // player is not targeting themselves
                 if user_id == give.target_user_id {
-                    return Err("this is a futile endeavour".into());
+                    return Err(GameError::SelfTarget {
+                        action: "give".into(),
+                    });
                 }
                 // player has lives
                 player_flux.is_alive()?;
@@ -725,14 +1053,13 @@ impl Game {
                 // player has lives
                 player_flux.is_alive()?;
                 // player has enough action points and correct cost estimate
-                if player_flux.action_points < RANGE_UPGRADE_COST
-                    || range_upgrade.point_cost != RANGE_UPGRADE_COST
-                {
-                    return Err(format!(
-                        "{} action points required to upgrade range",
-                        RANGE_UPGRADE_COST
-                    ));
+                if range_upgrade.point_cost != RANGE_UPGRADE_COST {
+                    return Err(GameError::WrongCost {
+                        required: RANGE_UPGRADE_COST,
+                        requested: range_upgrade.point_cost,
+                    });
                 }
+                player_flux.has_action_points(RANGE_UPGRADE_COST)?;
                 // <EXECUTE>
                 // exchange action points for range
                 player_flux.action_points -= RANGE_UPGRADE_COST;
@@ -748,12 +1075,13 @@ impl Game {
                 self.check_in_prog()?;
                 // player has lives
                 player_flux.is_alive()?;
-                if player_flux.action_points < HEAL_COST || heal.point_cost != HEAL_COST {
-                    return Err(format!(
-                        "{} action points required to heal",
-                        RANGE_UPGRADE_COST
-                    ));
+                if heal.point_cost != HEAL_COST {
+                    return Err(GameError::WrongCost {
+                        required: HEAL_COST,
+                        requested: heal.point_cost,
+                    });
                 }
+                player_flux.has_action_points(HEAL_COST)?;
                 // <EXECUTE>
                 // exchange action points for life
                 player_flux.action_points -= HEAL_COST;
@@ -769,7 +1097,9 @@ impl Game {
                 let ReviveAction { target_user_id } = rev;
                 self.check_in_prog()?;
                 if user_id == target_user_id {
-                    return Err("you can't revive yourself".into());
+                    return Err(GameError::SelfTarget {
+                        action: "revive".into(),
+                    });
                 }
                 // player has lives
                 player_flux.is_alive()?;
@@ -801,7 +1131,10 @@ impl Game {
                 })
             }
             ActionType::Curse(curse) => {
-                let CurseAction { target_user_id } = curse;
+                let CurseAction {
+                    target_user_id,
+                    preferences,
+                } = curse;
                 // <VALIDATE>
                 self.check_in_prog()?;
 
@@ -810,15 +1143,18 @@ impl Game {
                     let target_flux = self.clone_player(&target_user_id)?;
                     target_flux.is_alive()?;
                     // <EXECUTE>
-                    self.curse_election
-                        .vote(&user_id, vec![target_user_id.clone()])?;
+                    let mut prefs = vec![target_user_id.clone()];
+                    prefs.extend(preferences.iter().cloned());
+                    self.curse_election.vote(&user_id, prefs)?;
                     ActionTypeEvent::Curse(CurseAction {
                         target_user_id: Some(target_user_id.into()),
+                        preferences: preferences.clone(),
                     })
                 } else {
                     self.curse_election.remove_ballot(user_id)?;
                     ActionTypeEvent::Curse(CurseAction {
                         target_user_id: None,
+                        preferences: vec![],
                     })
                 };
                 // return action event
@@ -835,14 +1171,14 @@ impl Game {
                         let RedeemTileHearts { pos, new_lives: _ } = tile_hearts;
                         // check player in position
                         if pos != &player_flux.pos {
-                            return Err("player not in position".to_owned());
+                            return Err(GameError::NotInPosition { pos: pos.clone() });
                         }
                         // check position has hearts
                         let board_lives = self
                             .board_hearts
                             .map
                             .get_mut(&pos.key())
-                            .ok_or("position heartless".to_owned())?;
+                            .ok_or_else(|| GameError::NoHearts { pos: pos.clone() })?;
                         // add hearts to player
                         player_flux.lives += *board_lives;
                         *board_lives = 0;
@@ -872,6 +1208,7 @@ impl Game {
         // apply player copy
         self.players
             .insert(player_flux.user_id.clone(), player_flux);
+        self.version += 1;
         Ok((
             PlayerResponse {
                 game_id: self.game_id.clone(),
@@ -888,15 +1225,112 @@ impl Game {
 
     pub fn get_player_action(&self, player_id: &str) -> PlayerResponse {
         // TODO match arm for all types of ActionTypeEvent
+        let prefs = self.curse_election.get_voter_prefs(player_id);
         PlayerResponse {
             action: ActionTypeEvent::Curse(CurseAction {
                 target_user_id: self.curse_election.get_voter_ballot(player_id),
+                preferences: prefs.into_iter().skip(1).collect(),
             }),
             user_id: player_id.into(),
             game_id: self.game_id.to_owned(),
             phase: self.phase.clone(),
         }
     }
+
+    /// changes to the public game since the last update, `None` if nothing changed
+    /// players get a full snapshot when they join or resync, updates after that
+    pub fn take_update(&mut self) -> Option<GameUpdate> {
+        let state = serde_json::to_value(&*self).expect("game serializes");
+        let patch = patch::diff(&self.published, &state);
+        if patch.is_empty() {
+            return None;
+        }
+        let from_version = self.published_version;
+        let checksum = patch::checksum(&state);
+        self.published = state;
+        self.published_version = self.version;
+        Some(GameUpdate {
+            game_id: self.game_id.clone(),
+            from_version,
+            version: self.version,
+            patch,
+            checksum,
+        })
+    }
+
+    /// check user may join without an invite, players rejoining are always let in
+    /// `verified_hash` is the password hash the user's password was verified against,
+    /// it must still be the game's in case the password changed while it was verified
+    pub fn check_access(
+        &self,
+        user_id: &str,
+        verified_hash: Option<&str>,
+    ) -> Result<(), GameError> {
+        if self.players.contains_key(user_id) {
+            return Ok(());
+        }
+        match self.config.visibility {
+            Visibility::Private => Err(GameError::InviteRequired),
+            Visibility::Password
+                if verified_hash.is_none() || verified_hash != self.password_hash.as_deref() =>
+            {
+                Err(GameError::WrongPassword)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// user has to give the game password to join without an invite
+    pub fn requires_password(&self, user_id: &str) -> bool {
+        self.config.visibility == Visibility::Password && !self.players.contains_key(user_id)
+    }
+
+    /// hash to verify the password of users joining against
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+
+    /// players that can still join
+    pub fn open_seats(&self) -> usize {
+        match self.phase {
+            GamePhase::Init => {
+                usize::from(self.config.max_players).saturating_sub(self.players.len())
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn summary(&self) -> GameSummary {
+        let mut players: Vec<String> = self.players.keys().cloned().collect();
+        players.sort();
+        GameSummary {
+            game_id: self.game_id.clone(),
+            phase: self.phase.clone(),
+            host_user_id: self.host_user_id.clone(),
+            players,
+            open_seats: self.open_seats(),
+            board_size: self.board.size,
+            config: self.config.clone(),
+            version: self.version,
+        }
+    }
+
+    /// game as sent to its players, without anyone's private data
+    pub fn public_view(&self) -> Value {
+        serde_json::to_value(self).expect("game serializes")
+    }
+
+    /// game as seen by a player, without side effects
+    pub fn player_state(&self, player_id: &str) -> Result<PlayerGameState, GameError> {
+        let player = self.clone_player(player_id)?;
+        let game = self.public_view();
+        Ok(PlayerGameState {
+            checksum: patch::checksum(&game),
+            game,
+            action_points: player.action_points,
+            curse: self.get_player_action(player_id),
+        })
+    }
 }
 
 fn from_now(to_secs: u64) -> u64 {
@@ -907,3 +1341,132 @@ fn from_now(to_secs: u64) -> u64 {
         .as_secs();
     since_the_epoch + to_secs
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::{PasswordConfig, PasswordHasher};
+    use actix_web::web::Query;
+
+    #[test]
+    fn test_game_filter() {
+        let mut game = Game::new("g".into(), 8, GameConfig::new(), rand::thread_rng());
+        game.set_host("a".into()).unwrap();
+        game.insert_player("b".into()).unwrap();
+        let open = game.open_seats();
+        assert_eq!(open, usize::from(game.config.max_players) - 2);
+        assert_eq!(game.summary().players, vec!["a", "b"]);
+
+        let parse = |query: &str| Query::<GameFilter>::from_query(query).map(Query::into_inner);
+        let filter = |query: &str| parse(query).unwrap();
+        assert!(filter("").matches(&game));
+        assert!(filter("phase=Init").matches(&game));
+        assert!(!filter("phase=InProg").matches(&game));
+        assert!(filter(&format!("min_open_seats={}", open)).matches(&game));
+        assert!(!filter(&format!("min_open_seats={}", open + 1)).matches(&game));
+        let turn_time = game.config.turn_time_secs;
+        assert!(filter(&format!("max_turn_time_secs={}&board_size=8", turn_time)).matches(&game));
+        assert!(!filter(&format!("min_turn_time_secs={}", turn_time + 1)).matches(&game));
+        assert!(parse("seats=1").is_err());
+
+        game.phase = GamePhase::InProg;
+        assert_eq!(game.open_seats(), 0);
+        assert!(!filter("min_open_seats=1").matches(&game));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config = GameConfig::new();
+        assert!(config.validate(8).is_ok());
+        assert!(matches!(config.validate(0), Err(GameError::EmptyBoard)));
+        assert!(matches!(
+            config.validate(1),
+            Err(GameError::PlayersWontFit { board_size: 1, .. })
+        ));
+        let mut game = Game::new("g".into(), 8, config, rand::thread_rng());
+        assert!(matches!(
+            game.configure(&ConfigGameOp::BoardSize(0)),
+            Err(GameError::EmptyBoard)
+        ));
+    }
+
+    #[test]
+    fn test_player_state() {
+        let hasher = PasswordHasher::new(PasswordConfig {
+            memory_kib: 256,
+            iterations: 1,
+            parallelism: 1,
+        })
+        .unwrap();
+        let mut game = Game::new("g".into(), 8, GameConfig::new(), rand::thread_rng());
+        game.set_host("a".into()).unwrap();
+        game.configure(&ConfigGameOp::Password(hasher.hash("pw").unwrap()))
+            .unwrap();
+        let state = game.player_state("a").unwrap();
+        assert_eq!(state.action_points, game.players["a"].action_points);
+        assert_eq!(state.game["version"], game.version);
+        assert_eq!(state.checksum, patch::checksum(&state.game));
+        let curse = serde_json::to_value(&state.curse).unwrap();
+        assert_eq!(curse["action"]["Curse"]["target_user_id"], Value::Null);
+        // private data stays out of the public game
+        assert!(state.game.get("password_hash").is_none());
+        assert!(!serde_json::to_string(&state)
+            .unwrap()
+            .contains("$argon2id$"));
+        assert!(state.game.get("curse_election").is_none());
+
+        // changes bump the version the state is sent with
+        let version = game.version;
+        game.configure(&ConfigGameOp::TurnTimeSecs(game.config.turn_time_secs + 1))
+            .unwrap();
+        let changed = game.player_state("a").unwrap();
+        assert_eq!(changed.game["version"], version + 1);
+        assert_ne!(changed.checksum, state.checksum);
+
+        assert!(matches!(
+            game.player_state("b"),
+            Err(GameError::PlayerNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_game_password() {
+        let mut game = Game::new("g".into(), 8, GameConfig::new(), rand::thread_rng());
+        game.set_host("a".into()).unwrap();
+        assert!(game.check_access("b", None).is_ok());
+        assert!(!game.requires_password("b"));
+        assert_eq!(
+            game.configure(&ConfigGameOp::Visibility(Visibility::Password)),
+            Err(GameError::NoPassword)
+        );
+        game.configure(&ConfigGameOp::Password("hash".into()))
+            .unwrap();
+        assert_eq!(game.config.visibility, Visibility::Password);
+        assert_eq!(game.password_hash(), Some("hash"));
+        assert!(game.requires_password("b"));
+        assert_eq!(game.check_access("b", None), Err(GameError::WrongPassword));
+        // verified against a hash the game no longer has
+        assert_eq!(
+            game.check_access("b", Some("old hash")),
+            Err(GameError::WrongPassword)
+        );
+        assert!(game.check_access("b", Some("hash")).is_ok());
+        // players already in the game rejoin without it
+        assert!(!game.requires_password("a"));
+        assert!(game.check_access("a", None).is_ok());
+        // the hash is never sent to clients
+        assert!(!game.public_view().to_string().contains("hash"));
+        game.configure(&ConfigGameOp::Visibility(Visibility::Public))
+            .unwrap();
+        assert!(game.check_access("b", None).is_ok());
+        assert_eq!(game.password_hash(), None);
+        assert_eq!(
+            game.configure(&ConfigGameOp::Visibility(Visibility::Password)),
+            Err(GameError::NoPassword)
+        );
+        game.configure(&ConfigGameOp::Visibility(Visibility::Private))
+            .unwrap();
+        assert_eq!(game.check_access("b", None), Err(GameError::InviteRequired));
+        assert!(game.check_access("a", None).is_ok());
+    }
+}
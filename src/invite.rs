@@ -0,0 +1,120 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::token::gen_token;
+
+/// characters in an invite code, short enough to share but too many to guess
+pub const INVITE_CODE_LEN: usize = 12;
+/// how long an invite is valid for if the host doesn't say
+pub const INVITE_TTL_SECS: u64 = 60 * 60 * 24;
+/// longest an invite can be valid for
+pub const MAX_INVITE_TTL_SECS: u64 = 60 * 60 * 24 * 7;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Invite {
+    /// url safe so join links can carry it, joining with it works like joining with the game ID
+    pub code: String,
+    pub game_id: String,
+    pub expires_unix: u64,
+    /// used up by the first player to join with it
+    pub single_use: bool,
+    /// players that have joined with it
+    pub uses: u32,
+}
+
+impl Invite {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.expires_unix
+    }
+}
+
+/// invite codes hosts have created for their games
+#[derive(Debug, Default)]
+pub struct InviteStore {
+    invites: HashMap<String, Invite>,
+}
+
+impl InviteStore {
+    /// create invite valid for `ttl_secs`, kept between 1 second and `MAX_INVITE_TTL_SECS`
+    pub fn mint(
+        &mut self,
+        game_id: &str,
+        ttl_secs: Option<u64>,
+        single_use: bool,
+        now: u64,
+    ) -> Invite {
+        let ttl_secs = ttl_secs
+            .unwrap_or(INVITE_TTL_SECS)
+            .clamp(1, MAX_INVITE_TTL_SECS);
+        let code = loop {
+            let code = gen_token()[..INVITE_CODE_LEN].to_owned();
+            if !self.invites.contains_key(&code) {
+                break code;
+            }
+        };
+        let invite = Invite {
+            code: code.clone(),
+            game_id: game_id.to_owned(),
+            expires_unix: now + ttl_secs,
+            single_use,
+            uses: 0,
+        };
+        self.invites.insert(code, invite.clone());
+        invite
+    }
+
+    /// invite with code, expired invites are returned until purged
+    pub fn get(&self, code: &str) -> Option<&Invite> {
+        self.invites.get(code)
+    }
+
+    /// count a player joining with the invite, single use invites are removed
+    pub fn redeem(&mut self, code: &str) -> Option<Invite> {
+        let invite = self.invites.get_mut(code)?;
+        invite.uses += 1;
+        let invite = invite.clone();
+        if invite.single_use {
+            self.invites.remove(code);
+        }
+        Some(invite)
+    }
+
+    pub fn purge_expired(&mut self, now: u64) {
+        self.invites.retain(|_, invite| !invite.is_expired(now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invites() {
+        let mut store = InviteStore::default();
+        let invite = store.mint("g", None, false, 100);
+        assert_eq!(invite.code.len(), INVITE_CODE_LEN);
+        assert_eq!(invite.expires_unix, 100 + INVITE_TTL_SECS);
+        assert_eq!(store.get(&invite.code), Some(&invite));
+        assert_eq!(store.redeem(&invite.code).unwrap().uses, 1);
+        assert_eq!(store.redeem(&invite.code).unwrap().uses, 2);
+
+        let once = store.mint("g", Some(10), true, 100);
+        assert_ne!(once.code, invite.code);
+        assert!(!once.is_expired(109));
+        assert!(once.is_expired(110));
+        assert_eq!(store.redeem(&once.code).unwrap().uses, 1);
+        assert_eq!(store.redeem(&once.code), None);
+        assert_eq!(store.get(&once.code), None);
+
+        // ttl is kept in range
+        let long = store.mint("g", Some(u64::MAX), false, 100);
+        assert_eq!(long.expires_unix, 100 + MAX_INVITE_TTL_SECS);
+        let short = store.mint("g", Some(0), false, 100);
+        assert_eq!(short.expires_unix, 101);
+
+        store.purge_expired(101 + INVITE_TTL_SECS);
+        assert_eq!(store.get(&invite.code), None);
+        assert!(store.get(&long.code).is_some());
+        assert_eq!(store.redeem("nope"), None);
+    }
+}
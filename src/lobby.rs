@@ -0,0 +1,194 @@
+use actix::prelude::*;
+use log::debug;
+use std::collections::HashSet;
+
+use crate::common::{MsgResult, Visibility};
+use crate::game::{Game, GameFilter, GameSummary};
+use crate::relay_server::Message;
+
+/// games anyone can find, `Unlisted` and `Private` games are only known to those told of them
+pub fn is_listed(game: &Game) -> bool {
+    matches!(
+        game.config.visibility,
+        Visibility::Public | Visibility::Password
+    )
+}
+
+/// listed games matching filter, sorted by ID
+pub fn list<'a>(games: impl Iterator<Item = &'a Game>, filter: &GameFilter) -> Vec<GameSummary> {
+    let mut games: Vec<GameSummary> = games
+        .filter(|game| is_listed(game) && filter.matches(game))
+        .map(Game::summary)
+        .collect();
+    games.sort_by(|a, b| a.game_id.cmp(&b.game_id));
+    games
+}
+
+struct LobbyWatcher {
+    addr: Recipient<Message>,
+    filter: GameFilter,
+    /// IDs of games the session has been sent and not told to remove
+    listed: HashSet<String>,
+}
+
+/// sessions watching the lobby, sent `/lobby_game` when a game they'd list changes
+/// and `/lobby_remove` when a game they listed no longer matches
+#[derive(Default)]
+pub struct Lobby {
+    watchers: Vec<LobbyWatcher>,
+}
+
+impl Lobby {
+    /// start watching, or change the filter of a session already watching
+    /// returns the games listed for the session
+    pub fn watch<'a>(
+        &mut self,
+        addr: Recipient<Message>,
+        filter: GameFilter,
+        games: impl Iterator<Item = &'a Game>,
+    ) -> Vec<GameSummary> {
+        let list = list(games, &filter);
+        let listed = list.iter().map(|game| game.game_id.clone()).collect();
+        self.watchers.retain(|w| w.addr != addr);
+        self.watchers.push(LobbyWatcher {
+            addr,
+            filter,
+            listed,
+        });
+        list
+    }
+
+    pub fn unwatch(&mut self, addr: &Recipient<Message>) {
+        self.watchers.retain(|w| &w.addr != addr);
+    }
+
+    /// tell watchers about a change to the game's players, config or phase
+    /// watchers whose session has stopped are dropped, as are watchers whose queue is full,
+    /// which are told to list games again once they catch up
+    pub fn publish(&mut self, game: &Game) {
+        let summary = MsgResult::lobby_game(&game.summary())
+            .unwrap_or_else(|e| MsgResult::error("lobby_game", &e));
+        let listed = is_listed(game);
+        self.watchers.retain_mut(|w| {
+            let text = if listed && w.filter.matches(game) {
+                w.listed.insert(game.game_id.clone());
+                summary.clone()
+            } else if w.listed.remove(&game.game_id) {
+                MsgResult::lobby_remove(&game.game_id)
+            } else {
+                return true;
+            };
+            match w.addr.try_send(Message::new(text)) {
+                Ok(()) => true,
+                Err(SendError::Full(_)) => {
+                    debug!("[srv/m] lobby watcher too slow, dropped");
+                    // unconditional so the notice isn't lost to the full queue
+                    let text = MsgResult::error("list_games", "lobby updates stopped, too slow");
+                    let _ = w.addr.do_send(Message::new(text));
+                    false
+                }
+                Err(SendError::Closed(_)) => {
+                    debug!("[srv/m] lobby watcher dropped, session stopped");
+                    false
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ConfigGameOp;
+    use crate::game::GameConfig;
+    use std::sync::{Arc, Mutex};
+
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Collect {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Collect {
+        type Result = ();
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.text);
+        }
+    }
+
+    fn game(game_id: &str, visibility: Visibility) -> Game {
+        let mut config = GameConfig::new();
+        config.visibility = visibility;
+        let mut game = Game::new(game_id.into(), 8, config, rand::thread_rng());
+        game.set_host(format!("{}_host", game_id)).unwrap();
+        game
+    }
+
+    #[actix_rt::test]
+    async fn test_lobby() {
+        let texts = Arc::new(Mutex::new(Vec::new()));
+        let addr = Collect(texts.clone()).start().recipient();
+        let public = game("a", Visibility::Public);
+        let mut unlisted = game("b", Visibility::Unlisted);
+        let mut full = game("c", Visibility::Public);
+
+        let mut lobby = Lobby::default();
+        let filter = GameFilter {
+            min_open_seats: Some(1),
+            ..GameFilter::default()
+        };
+        let games = [&public, &unlisted, &full];
+        let list = lobby.watch(addr, filter, games.iter().copied());
+        let ids: Vec<&str> = list.iter().map(|g| g.game_id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "c"]);
+
+        // unlisted games stay hidden until made public
+        unlisted.insert_player("x".into()).unwrap();
+        lobby.publish(&unlisted);
+        unlisted
+            .configure(&ConfigGameOp::Visibility(Visibility::Public))
+            .unwrap();
+        lobby.publish(&unlisted);
+        // games that fill up no longer match
+        full.configure(&ConfigGameOp::MaxPlayers(2)).unwrap();
+        lobby.publish(&full);
+        full.insert_player("y".into()).unwrap();
+        lobby.publish(&full);
+        lobby.publish(&full);
+
+        actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        let texts = texts.lock().unwrap();
+        assert_eq!(texts.len(), 3);
+        assert!(texts[0].starts_with("/lobby_game {\"game_id\":\"b\""));
+        assert!(texts[1].starts_with("/lobby_game {\"game_id\":\"c\""));
+        assert_eq!(texts[2], "/lobby_remove {\"game_id\":\"c\"}");
+    }
+
+    #[actix_rt::test]
+    async fn test_slow_watcher() {
+        let texts = Arc::new(Mutex::new(Vec::new()));
+        let collect = texts.clone();
+        let addr = Collect::create(|ctx| {
+            ctx.set_mailbox_capacity(1);
+            Collect(collect)
+        });
+        let mut lobby = Lobby::default();
+        let mut public = game("a", Visibility::Public);
+        lobby.watch(addr.recipient(), GameFilter::default(), std::iter::empty());
+
+        // nothing is handled until the test yields, the second update overflows the queue
+        for user_id in ["x", "y", "z"] {
+            public.insert_player(user_id.into()).unwrap();
+            lobby.publish(&public);
+        }
+        assert!(lobby.watchers.is_empty());
+        actix_rt::time::delay_for(std::time::Duration::from_millis(10)).await;
+        let texts = texts.lock().unwrap();
+        assert_eq!(texts.len(), 2);
+        assert!(texts[0].starts_with("/lobby_game "));
+        assert_eq!(
+            texts[1],
+            "/error list_games: lobby updates stopped, too slow"
+        );
+    }
+}
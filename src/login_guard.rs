@@ -0,0 +1,200 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// failed login attempt limits for a single key (user ID or IP address)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AttemptLimit {
+    /// failures allowed before the key is locked
+    pub free_attempts: u32,
+    /// lockout after the first failure past `free_attempts`, doubled for each further failure
+    pub base_lockout_secs: u64,
+    /// longest lockout
+    pub max_lockout_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "LoginGuardConfig::new", deny_unknown_fields)]
+pub struct LoginGuardConfig {
+    pub per_user: AttemptLimit,
+    pub per_ip: AttemptLimit,
+    /// failures are forgotten once a key has had no failures for this long
+    pub forget_after_secs: u64,
+}
+
+impl LoginGuardConfig {
+    pub fn new() -> LoginGuardConfig {
+        LoginGuardConfig {
+            per_user: AttemptLimit {
+                free_attempts: 5,
+                base_lockout_secs: 2,
+                max_lockout_secs: 60 * 15,
+            },
+            per_ip: AttemptLimit {
+                free_attempts: 20,
+                base_lockout_secs: 2,
+                max_lockout_secs: 60 * 15,
+            },
+            forget_after_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attempts {
+    failures: u32,
+    last_failure_unix: u64,
+    locked_until_unix: u64,
+}
+
+impl Attempts {
+    fn fail(&mut self, limit: &AttemptLimit, now: u64) {
+        self.failures += 1;
+        self.last_failure_unix = now;
+        if self.failures > limit.free_attempts {
+            // saturate the exponent so long streaks don't overflow
+            let doublings = (self.failures - limit.free_attempts - 1).min(32);
+            let lockout = limit
+                .base_lockout_secs
+                .saturating_mul(1 << doublings)
+                .min(limit.max_lockout_secs);
+            self.locked_until_unix = now + lockout;
+        }
+    }
+}
+
+/// tracks failed logins per user ID and per IP address, locking them out with exponential backoff
+pub struct LoginGuard {
+    config: LoginGuardConfig,
+    users: HashMap<String, Attempts>,
+    ips: HashMap<String, Attempts>,
+}
+
+impl LoginGuard {
+    pub fn new(config: LoginGuardConfig) -> LoginGuard {
+        LoginGuard {
+            config,
+            users: HashMap::new(),
+            ips: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &LoginGuardConfig {
+        &self.config
+    }
+
+    /// error with seconds until retry if user or IP is locked out
+    pub fn check(&self, user_id: &str, ip: Option<&str>, now: u64) -> Result<(), u64> {
+        let user_lock = self.users.get(user_id).map_or(0, |a| a.locked_until_unix);
+        let ip_lock = ip
+            .and_then(|ip| self.ips.get(ip))
+            .map_or(0, |a| a.locked_until_unix);
+        let locked_until = user_lock.max(ip_lock);
+        if locked_until > now {
+            return Err(locked_until - now);
+        }
+        Ok(())
+    }
+
+    pub fn record_failure(&mut self, user_id: &str, ip: Option<&str>, now: u64) {
+        let new = || Attempts {
+            failures: 0,
+            last_failure_unix: now,
+            locked_until_unix: 0,
+        };
+        self.users
+            .entry(user_id.to_owned())
+            .or_insert_with(new)
+            .fail(&self.config.per_user, now);
+        if let Some(ip) = ip {
+            self.ips
+                .entry(ip.to_owned())
+                .or_insert_with(new)
+                .fail(&self.config.per_ip, now);
+        }
+    }
+
+    /// clear user's failures, IP failures are kept so one known account can't reset them
+    pub fn record_success(&mut self, user_id: &str) {
+        self.users.remove(user_id);
+    }
+
+    /// forget keys that are unlocked and have had no recent failures
+    pub fn purge(&mut self, now: u64) {
+        let forget_after = self.config.forget_after_secs;
+        let keep =
+            |a: &Attempts| a.locked_until_unix > now || a.last_failure_unix + forget_after > now;
+        self.users.retain(|_, a| keep(a));
+        self.ips.retain(|_, a| keep(a));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> LoginGuard {
+        LoginGuard::new(LoginGuardConfig {
+            per_user: AttemptLimit {
+                free_attempts: 2,
+                base_lockout_secs: 10,
+                max_lockout_secs: 35,
+            },
+            per_ip: AttemptLimit {
+                free_attempts: 4,
+                base_lockout_secs: 100,
+                max_lockout_secs: 100,
+            },
+            forget_after_secs: 1000,
+        })
+    }
+
+    #[test]
+    fn test_user_backoff() {
+        let mut g = guard();
+        g.record_failure("a", None, 0);
+        g.record_failure("a", None, 0);
+        // free attempts used up without locking
+        assert_eq!(g.check("a", None, 0), Ok(()));
+        g.record_failure("a", None, 0);
+        assert_eq!(g.check("a", None, 0), Err(10));
+        assert_eq!(g.check("a", None, 4), Err(6));
+        assert_eq!(g.check("a", None, 10), Ok(()));
+        // lockout doubles then caps
+        g.record_failure("a", None, 10);
+        assert_eq!(g.check("a", None, 10), Err(20));
+        g.record_failure("a", None, 30);
+        assert_eq!(g.check("a", None, 30), Err(35));
+        // other users are unaffected
+        assert_eq!(g.check("b", None, 30), Ok(()));
+        // success clears the user's failures
+        g.record_success("a");
+        assert_eq!(g.check("a", None, 30), Ok(()));
+    }
+
+    #[test]
+    fn test_ip_lockout_spans_users() {
+        let mut g = guard();
+        for (i, user) in ["a", "b", "c", "d", "e"].iter().enumerate() {
+            assert_eq!(g.check(user, Some("1.2.3.4"), 0), Ok(()), "{}", i);
+            g.record_failure(user, Some("1.2.3.4"), 0);
+        }
+        assert_eq!(g.check("f", Some("1.2.3.4"), 0), Err(100));
+        assert_eq!(g.check("f", Some("5.6.7.8"), 0), Ok(()));
+        // success for one user doesn't unlock the IP
+        g.record_success("a");
+        assert_eq!(g.check("a", Some("1.2.3.4"), 0), Err(100));
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut g = guard();
+        g.record_failure("a", Some("1.2.3.4"), 0);
+        g.purge(999);
+        assert_eq!(g.users.len(), 1);
+        assert_eq!(g.ips.len(), 1);
+        g.purge(1000);
+        assert_eq!(g.users.len(), 0);
+        assert_eq!(g.ips.len(), 0);
+    }
+}
@@ -1,25 +1,44 @@
 use actix::prelude::*;
 use actix_cors::Cors;
-use actix_redis::RedisSession;
 use actix_session::Session;
 use actix_web::{
+    http::header,
     middleware, web,
     web::{get, post, resource},
-    App, HttpResponse, HttpServer, Result,
+    App, HttpRequest, HttpResponse, HttpServer, Result,
 };
 
+use futures::future;
+use log::warn;
 use serde::{Deserialize, Serialize};
 
 use crate::{common::gen_rng_string, ws_session::ws_route};
 
+mod account;
+mod command;
 mod common;
+mod config;
 mod election;
 mod game;
+mod invite;
+mod lobby;
+mod login_guard;
+mod matchmaking;
+mod metrics;
+mod password;
+mod patch;
+mod protocol;
+mod rate_limit;
 mod relay_server;
+mod services;
+mod session_backend;
+mod sse_session;
+mod token;
+mod user_store;
 mod utils;
 mod ws_session;
 
-use common::Identity;
+use common::{Fail, Identity};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct IndexResponse {
@@ -27,50 +46,106 @@ pub struct IndexResponse {
     msg: Option<String>,
 }
 
+/// user the HTTP session is logged in as
+/// the session's token stays out of the response so scripts can't read it
 async fn index(session: Session) -> Result<HttpResponse> {
     let user_id: Option<String> = session.get::<String>("user_id").unwrap();
-    let msg: Option<String> = session.get::<String>("token").unwrap();
-    Ok(HttpResponse::Ok().json(IndexResponse { user_id, msg }))
+    Ok(HttpResponse::Ok().json(IndexResponse { user_id, msg: None }))
 }
 
-async fn login(
-    user: web::Json<Identity>,
+/// server counters in the Prometheus text format
+async fn metrics(metrics: web::Data<metrics::Metrics>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render())
+}
+
+/// respond to `Connect` or `Register` result, storing user in session on success
+fn connect_response(
+    res: relay_server::ConnectResult,
+    user_id: String,
     session: Session,
-    relay_data: web::Data<Addr<relay_server::RelayServer>>,
 ) -> Result<HttpResponse> {
-    let Identity { user_id, password } = user.into_inner();
-    let res = relay_data
-        .send(relay_server::Connect {
-            user: relay_server::User {
-                user_id: user_id.clone(),
-                password: password.clone(),
-            },
-            addr: None,
-        })
-        .await
-        .expect("login contact with relay failed");
     match res {
-        relay_server::ConnectResult::Fail(_) => {
-            Ok(HttpResponse::Unauthorized().json(IndexResponse {
+        relay_server::ConnectResult::Fail(fail) => {
+            let mut res = match fail {
+                Fail::Internal => HttpResponse::InternalServerError(),
+                Fail::UserExists => HttpResponse::Conflict(),
+                Fail::InvalidUserId(_) | Fail::WeakPassword(_) => HttpResponse::BadRequest(),
+                Fail::Password | Fail::UnknownUser | Fail::Token(_) => HttpResponse::Unauthorized(),
+                Fail::Locked { retry_after_secs } => {
+                    let mut res = HttpResponse::TooManyRequests();
+                    res.header(header::RETRY_AFTER, retry_after_secs.to_string());
+                    res
+                }
+            };
+            Ok(res.json(IndexResponse {
                 user_id: Some(user_id),
-                msg: Some("pasword does not match saved".to_owned()),
+                msg: Some(fail.to_string()),
             }))
         }
         relay_server::ConnectResult::Success(s) => {
-            session.set("user_id", &user_id)?;
-            session.set("token", &password)?;
+            session.set("user_id", &s.user_id)?;
+            session.set("token", &s.token)?;
             session.renew();
             Ok(HttpResponse::Ok().json(IndexResponse {
-                user_id: Some(user_id),
+                user_id: Some(s.user_id),
                 msg: Some(s.alert),
             }))
         }
     }
 }
 
-async fn logout(session: Session) -> Result<HttpResponse> {
+async fn login(
+    req: HttpRequest,
+    user: web::Json<Identity>,
+    session: Session,
+    relay_data: web::Data<Addr<relay_server::RelayServer>>,
+) -> Result<HttpResponse> {
+    let Identity { user_id, password } = user.into_inner();
+    let res = relay_data
+        .send(relay_server::Connect {
+            user_id: user_id.clone(),
+            password,
+            addr: None,
+            ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        })
+        .await
+        .expect("login contact with relay failed");
+    connect_response(res, user_id, session)
+}
+
+async fn register(
+    user: web::Json<Identity>,
+    session: Session,
+    relay_data: web::Data<Addr<relay_server::RelayServer>>,
+) -> Result<HttpResponse> {
+    let Identity { user_id, password } = user.into_inner();
+    let res = relay_data
+        .send(relay_server::Register {
+            user_id: user_id.clone(),
+            password,
+            addr: None,
+        })
+        .await
+        .expect("register contact with relay failed");
+    connect_response(res, user_id, session)
+}
+
+async fn logout(
+    session: Session,
+    relay_data: web::Data<Addr<relay_server::RelayServer>>,
+) -> Result<HttpResponse> {
     let id: Option<String> = session.get("user_id")?;
     if let Some(x) = id {
+        // revoke the token issued at login so it can't verify a websocket session
+        let token: Option<String> = session.get("token")?;
+        if let Some(token) = token {
+            relay_data.do_send(relay_server::RevokeToken {
+                user_id: x.clone(),
+                token: Some(token),
+            });
+        }
         session.purge();
         Ok(format!("Logged out: {}", x).into())
     } else {
@@ -90,42 +165,262 @@ fn get_p_key() -> Vec<u8> {
     panic!("set PRIVATE_KEY in .env e.g {}", password);
 }
 
+/// persist users to `users_file` if set, otherwise keep them in memory
+fn get_user_store(config: &config::Config) -> Box<dyn user_store::UserStore> {
+    match &config.users_file {
+        Some(path) => Box::new(
+            user_store::FileUserStore::open(path)
+                .unwrap_or_else(|e| panic!("could not open users_file {}", e)),
+        ),
+        None => Box::new(user_store::MemoryUserStore::new()),
+    }
+}
+
+/// allow cross origin requests only from the configured origins, logging rejected origins
+fn get_cors(config: &config::CorsConfig) -> Cors {
+    let origins = config.allowed_origins.clone();
+    let cors = Cors::default()
+        .allowed_origin_fn(move |origin, _| {
+            let allowed = origins.iter().any(|o| o.as_bytes() == origin.as_bytes());
+            if !allowed {
+                warn!("rejected cross origin request from {:?}", origin);
+            }
+            allowed
+        })
+        .allowed_methods(config.allowed_methods.iter().map(String::as_str))
+        .allowed_headers(config.allowed_headers.iter().map(String::as_str))
+        .max_age(config.max_age_secs);
+    if config.supports_credentials {
+        cors.supports_credentials()
+    } else {
+        cors
+    }
+}
+
+/// load config from file, env and command line, exiting with a message if it is invalid
+fn get_config() -> config::Config {
+    let args = match config::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, config::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", config::USAGE);
+        std::process::exit(0);
+    }
+    config::Config::load(args, std::env::vars()).unwrap_or_else(|e| {
+        eprintln!("invalid config: {}", e);
+        std::process::exit(2);
+    })
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
+    // config errors and --help come before complaints about the environment
+    let config = get_config();
     let private_key = get_p_key();
-    std::env::set_var("RUST_LOG", "actix_web=info,actix_redis=info");
-    env_logger::init();
+    env_logger::Builder::new().parse_filters(&config.log).init();
 
-    let relay = relay_server::RelayServer::new().start();
+    let hasher = password::PasswordHasher::new(config.password.clone())
+        .expect("password hash parameters are validated");
+    let users = get_user_store(&config);
+    let login_guard = login_guard::LoginGuard::new(config.login_guard.clone());
+    let relay = relay_server::RelayServer::new(
+        users,
+        hasher,
+        login_guard,
+        config.game.clone(),
+        config.board_size,
+        config.outbound.clone(),
+        config.matchmaking.clone(),
+    )
+    .start();
+    let session_backend = config.session_backend().expect("session is validated");
+    let memory_sessions = session_backend::MemorySessionStore::default();
+    let bind = config.bind.clone();
+    // shared by every worker so counts are server wide
+    let server_metrics = web::Data::new(metrics::Metrics::default());
+    // commands may be posted to a different worker than the one streaming events
+    let sse_streams = web::Data::new(sse_session::SseStreams::default());
 
-    HttpServer::new(move || {
+    let metrics_bind = config.metrics_bind.clone();
+    let metrics_data = server_metrics.clone();
+
+    let server = HttpServer::new(move || {
         App::new()
-            // redis session middleware
-            .wrap(
-                Cors::permissive()
-                    // .allowed_origin("http://localhost:3000")
-                    // .allowed_origin("http://192.168.0.177:3000")
-                    // .allowed_methods(vec!["GET", "POST"])
-                    // .allowed_headers(vec![
-                    //     header::AUTHORIZATION,
-                    //     header::ACCEPT,
-                    //     header::CONTENT_TYPE,
-                    // ])
-                    // .supports_credentials()
-                    // .max_age(36000),
-            )
-            .wrap(RedisSession::new("127.0.0.1:6379", &private_key).cookie_http_only(false))
+            .wrap(get_cors(&config.cors))
+            // session middleware for the configured backend
+            .wrap(session_backend::SessionMiddleware::new(
+                session_backend.clone(),
+                &private_key,
+                memory_sessions.clone(),
+                config.session.secure_cookie,
+            ))
             // enable logger - always register actix-web Logger middleware last
             .wrap(middleware::Logger::default())
             .data(relay.clone())
+            .data(ws_session::WsConfig {
+                heartbeat: config.heartbeat.clone(),
+                protocol: config.protocol.clone(),
+                outbound: config.outbound.clone(),
+                rate_limit: config.rate_limit.clone(),
+                allowed_origins: config.cors.allowed_origins.clone(),
+            })
+            .app_data(server_metrics.clone())
+            .app_data(sse_streams.clone())
             .service(resource("/").route(get().to(index)))
             .service(resource("/login").route(post().to(login)))
+            .service(resource("/register").route(post().to(register)))
             .service(resource("/logout").route(get().to(logout)))
             .service(resource("/ws/").to(ws_route))
-        // .configure(services::config)
+            .service(resource("/events").route(get().to(sse_session::events_route)))
+            .service(resource("/events/{stream_id}").route(post().to(sse_session::command_route)))
+            .configure(services::config)
     })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+    .bind(bind)?
+    .run();
+    // metrics go on their own bind so they can stay off the public one
+    match metrics_bind {
+        Some(metrics_bind) => {
+            let metrics_server = HttpServer::new(move || {
+                App::new()
+                    .wrap(middleware::Logger::default())
+                    .app_data(metrics_data.clone())
+                    .configure(metrics_config)
+            })
+            .workers(1)
+            .bind(metrics_bind)?
+            .run();
+            future::try_join(server, metrics_server).await.map(|_| ())
+        }
+        None => server.await,
+    }
+}
+
+/// routes of the metrics server, kept apart from the public app
+fn metrics_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(resource("/metrics").route(get().to(metrics)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+    use std::sync::Mutex;
+
+    /// logger keeping messages so tests can check what was logged
+    struct Capture;
+
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+        fn log(&self, record: &log::Record) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    #[actix_rt::test]
+    async fn test_cors() {
+        log::set_logger(&Capture).ok();
+        log::set_max_level(log::LevelFilter::Warn);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(get_cors(&config::CorsConfig::new()))
+                .route("/", get().to(|| async { "ok" })),
+        )
+        .await;
+        let request = |origin: &str| {
+            test::TestRequest::get()
+                .uri("/")
+                .header(header::ORIGIN, origin)
+                .to_request()
+        };
+
+        let res = test::call_service(&mut app, request("http://localhost:3000")).await;
+        let headers = res.headers();
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(),
+            "http://localhost:3000"
+        );
+        assert_eq!(
+            headers
+                .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .unwrap(),
+            "true"
+        );
+
+        let res = test::call_service(&mut app, request("https://evil.example")).await;
+        assert!(res
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+        assert!(LOGGED
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|m| m.contains("rejected cross origin request") && m.contains("evil.example")));
+    }
+
+    #[actix_rt::test]
+    async fn test_index_hides_token() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(session_backend::SessionMiddleware::new(
+                    session_backend::SessionBackend::Memory,
+                    &[0; 32],
+                    session_backend::MemorySessionStore::default(),
+                    true,
+                ))
+                .route(
+                    "/login",
+                    get().to(|s: Session| async move {
+                        s.set("user_id", "alice").unwrap();
+                        s.set("token", "secret").unwrap();
+                        "ok"
+                    }),
+                )
+                .route("/", get().to(index)),
+        )
+        .await;
+        let res = test::call_service(
+            &mut app,
+            test::TestRequest::get().uri("/login").to_request(),
+        )
+        .await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let req = test::TestRequest::get()
+            .uri("/")
+            .cookie(cookie)
+            .to_request();
+        let body: IndexResponse = test::read_response_json(&mut app, req).await;
+        assert_eq!(
+            body,
+            IndexResponse {
+                user_id: Some("alice".into()),
+                msg: None,
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_metrics_config() {
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(metrics::Metrics::default()))
+                .configure(metrics_config),
+        )
+        .await;
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("bftt_throttled_commands_total"));
+    }
 }
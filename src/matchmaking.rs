@@ -0,0 +1,228 @@
+use serde::Deserialize;
+
+use crate::game::MIN_PLAYERS;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "MatchmakingConfig::new", deny_unknown_fields)]
+pub struct MatchmakingConfig {
+    /// players per matched game, games can't start with fewer than `MIN_PLAYERS`
+    pub players: usize,
+}
+
+impl MatchmakingConfig {
+    pub fn new() -> MatchmakingConfig {
+        MatchmakingConfig {
+            players: MIN_PLAYERS,
+        }
+    }
+
+    /// check players fit on the default board of `board_size` by `board_size`
+    pub fn validate(&self, board_size: u16) -> Result<(), String> {
+        if self.players < MIN_PLAYERS {
+            return Err(format!("players must be at least {}", MIN_PLAYERS));
+        }
+        if self.players > usize::from(board_size).pow(2) {
+            return Err(format!(
+                "{} players won't fit in a {} by {} board",
+                self.players, board_size, board_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// settings a user wants in a matched game, unset settings match anything
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatchPrefs {
+    pub turn_time_secs: Option<u64>,
+    pub board_size: Option<u16>,
+}
+
+impl MatchPrefs {
+    /// prefs satisfying both, `None` if they conflict
+    pub fn merge(&self, other: &MatchPrefs) -> Option<MatchPrefs> {
+        Some(MatchPrefs {
+            turn_time_secs: merge_pref(self.turn_time_secs, other.turn_time_secs)?,
+            board_size: merge_pref(self.board_size, other.board_size)?,
+        })
+    }
+}
+
+fn merge_pref<T: PartialEq>(a: Option<T>, b: Option<T>) -> Option<Option<T>> {
+    match (a, b) {
+        (Some(a), Some(b)) if a != b => None,
+        (Some(a), _) => Some(Some(a)),
+        (None, b) => Some(b),
+    }
+}
+
+/// user waiting for a match and the settings they want
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ticket {
+    pub user_id: String,
+    pub prefs: MatchPrefs,
+}
+
+/// users waiting for a game, oldest first
+#[derive(Debug, Default)]
+pub struct MatchQueue {
+    waiting: Vec<Ticket>,
+}
+
+impl MatchQueue {
+    /// queue user, replacing the prefs of a user already waiting without losing their place
+    pub fn enqueue(&mut self, user_id: &str, prefs: MatchPrefs) {
+        match self.waiting.iter_mut().find(|t| t.user_id == user_id) {
+            Some(ticket) => ticket.prefs = prefs,
+            None => self.waiting.push(Ticket {
+                user_id: user_id.to_owned(),
+                prefs,
+            }),
+        }
+    }
+
+    /// returns false if the user wasn't waiting
+    pub fn cancel(&mut self, user_id: &str) -> bool {
+        let len = self.waiting.len();
+        self.waiting.retain(|t| t.user_id != user_id);
+        self.waiting.len() != len
+    }
+
+    pub fn contains(&self, user_id: &str) -> bool {
+        self.waiting.iter().any(|t| t.user_id == user_id)
+    }
+
+    /// users waiting, including the user, whose prefs are compatible with the user's
+    pub fn compatible(&self, user_id: &str) -> usize {
+        let prefs = match self.waiting.iter().find(|t| t.user_id == user_id) {
+            Some(ticket) => &ticket.prefs,
+            None => return 0,
+        };
+        self.waiting
+            .iter()
+            .filter(|t| prefs.merge(&t.prefs).is_some())
+            .count()
+    }
+
+    /// put tickets taken for a match that couldn't start back at the front of the queue
+    /// users that queued again since keep their new prefs
+    pub fn requeue(&mut self, tickets: Vec<Ticket>) {
+        let tickets: Vec<Ticket> = tickets
+            .into_iter()
+            .filter(|t| !self.contains(&t.user_id))
+            .collect();
+        self.waiting.splice(0..0, tickets);
+    }
+
+    /// remove `players` users with compatible prefs, preferring those waiting longest
+    /// returns their tickets and the prefs they agree on
+    pub fn take_match(&mut self, players: usize) -> Option<(Vec<Ticket>, MatchPrefs)> {
+        for first in 0..self.waiting.len() {
+            let mut prefs = self.waiting[first].prefs.clone();
+            let mut group = vec![first];
+            for (i, ticket) in self.waiting.iter().enumerate().skip(first + 1) {
+                if group.len() == players {
+                    break;
+                }
+                if let Some(merged) = prefs.merge(&ticket.prefs) {
+                    prefs = merged;
+                    group.push(i);
+                }
+            }
+            if group.len() == players {
+                let mut i = 0;
+                let mut tickets = Vec::with_capacity(players);
+                self.waiting.retain(|t| {
+                    let matched = group.contains(&i);
+                    i += 1;
+                    if matched {
+                        tickets.push(t.clone());
+                    }
+                    !matched
+                });
+                return Some((tickets, prefs));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefs(turn_time_secs: Option<u64>, board_size: Option<u16>) -> MatchPrefs {
+        MatchPrefs {
+            turn_time_secs,
+            board_size,
+        }
+    }
+
+    fn user_ids(tickets: &[Ticket]) -> Vec<&str> {
+        tickets.iter().map(|t| t.user_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let any = MatchPrefs::default();
+        let fast = prefs(Some(10), None);
+        assert_eq!(any.merge(&fast), Some(fast.clone()));
+        assert_eq!(fast.merge(&any), Some(fast.clone()));
+        assert_eq!(fast.merge(&prefs(Some(30), None)), None);
+        assert_eq!(
+            fast.merge(&prefs(Some(10), Some(8))),
+            Some(prefs(Some(10), Some(8)))
+        );
+    }
+
+    #[test]
+    fn test_take_match() {
+        let mut queue = MatchQueue::default();
+        queue.enqueue("a", prefs(Some(10), None));
+        queue.enqueue("b", prefs(Some(30), None));
+        queue.enqueue("c", MatchPrefs::default());
+        queue.enqueue("d", prefs(None, Some(8)));
+        assert_eq!(queue.take_match(4), None);
+        assert_eq!(queue.compatible("a"), 3);
+        assert_eq!(queue.compatible("b"), 3);
+
+        // requeueing keeps the user's place
+        queue.enqueue("b", prefs(Some(10), None));
+        assert_eq!(queue.compatible("a"), 4);
+        queue.enqueue("e", MatchPrefs::default());
+        let (tickets, agreed) = queue.take_match(4).unwrap();
+        assert_eq!(user_ids(&tickets), vec!["a", "b", "c", "d"]);
+        assert_eq!(agreed, prefs(Some(10), Some(8)));
+        assert_eq!(tickets[0].prefs, prefs(Some(10), None));
+        assert!(queue.contains("e"));
+        assert!(!queue.contains("a"));
+
+        assert!(queue.cancel("e"));
+        assert!(!queue.cancel("e"));
+        assert_eq!(queue.compatible("e"), 0);
+    }
+
+    #[test]
+    fn test_take_match_skips_conflicts() {
+        let mut queue = MatchQueue::default();
+        queue.enqueue("slow", prefs(Some(60), None));
+        for user_id in ["a", "b", "c"] {
+            queue.enqueue(user_id, prefs(Some(10), None));
+        }
+        assert_eq!(queue.take_match(4), None);
+        queue.enqueue("d", MatchPrefs::default());
+        let (tickets, _) = queue.take_match(4).unwrap();
+        assert_eq!(user_ids(&tickets), vec!["a", "b", "c", "d"]);
+        assert!(queue.contains("slow"));
+
+        // users of a match that couldn't start go back ahead of the rest with their own prefs
+        queue.enqueue("b", prefs(Some(60), None));
+        queue.requeue(tickets);
+        assert_eq!(user_ids(&queue.waiting), vec!["a", "c", "d", "slow", "b"]);
+        assert_eq!(queue.compatible("b"), 3);
+        assert!(MatchmakingConfig::new().validate(8).is_ok());
+        assert!(MatchmakingConfig { players: 3 }.validate(8).is_err());
+        assert!(MatchmakingConfig { players: 5 }.validate(2).is_err());
+    }
+}
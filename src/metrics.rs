@@ -0,0 +1,73 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::rate_limit::CommandClass;
+
+/// server wide counters, served in the Prometheus text format at `/metrics`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// commands refused by a rate limit, indexed by `CommandClass`
+    throttled_commands: [AtomicU64; 4],
+    /// sessions that had at least one command throttled
+    throttled_sessions: AtomicU64,
+    /// sessions closed for exceeding their rate limits
+    rate_limit_disconnects: AtomicU64,
+}
+
+impl Metrics {
+    pub fn command_throttled(&self, class: CommandClass) {
+        self.throttled_commands[class as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_throttled(&self) {
+        self.throttled_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rate_limit_disconnect(&self) {
+        self.rate_limit_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# TYPE bftt_throttled_commands_total counter\n");
+        for class in CommandClass::ALL {
+            let count = self.throttled_commands[class as usize].load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "bftt_throttled_commands_total{{class=\"{}\"}} {}",
+                class.name(),
+                count
+            );
+        }
+        let counters = [
+            ("bftt_throttled_sessions_total", &self.throttled_sessions),
+            (
+                "bftt_rate_limit_disconnects_total",
+                &self.rate_limit_disconnects,
+            ),
+        ];
+        for (name, counter) in counters {
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.command_throttled(CommandClass::Action);
+        metrics.command_throttled(CommandClass::Action);
+        metrics.session_throttled();
+        let out = metrics.render();
+        assert!(out.contains("bftt_throttled_commands_total{class=\"action\"} 2\n"));
+        assert!(out.contains("bftt_throttled_commands_total{class=\"auth\"} 0\n"));
+        assert!(out.contains("bftt_throttled_sessions_total 1\n"));
+        assert!(out.contains("bftt_rate_limit_disconnects_total 0\n"));
+    }
+}
@@ -0,0 +1,186 @@
+use actix_web::error::BlockingError;
+use actix_web::web;
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use futures::{Future, FutureExt};
+use rand::{rngs::OsRng, RngCore};
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+/// length in bytes of the random salt generated for each hash
+const SALT_LEN: usize = 16;
+
+/// argon2id cost parameters used when hashing new passwords
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "PasswordConfig::new", deny_unknown_fields)]
+pub struct PasswordConfig {
+    /// memory cost in KiB
+    pub memory_kib: u32,
+    /// number of passes over memory
+    pub iterations: u32,
+    /// degree of parallelism
+    pub parallelism: u32,
+}
+
+impl PasswordConfig {
+    pub fn new() -> PasswordConfig {
+        PasswordConfig {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// result of a successful password verification
+#[derive(Debug, PartialEq)]
+pub enum Verified {
+    /// stored hash uses the current parameters
+    Current,
+    /// stored hash is valid but was made with outdated parameters, contains replacement hash
+    Rehashed(String),
+}
+
+/// hashes and verifies passwords as salted argon2id PHC strings
+#[derive(Clone)]
+pub struct PasswordHasher {
+    config: PasswordConfig,
+    argon2: Argon2<'static>,
+}
+
+impl PasswordHasher {
+    pub fn new(config: PasswordConfig) -> Result<PasswordHasher, String> {
+        let params = Params::new(
+            config.memory_kib,
+            config.iterations,
+            config.parallelism,
+            None,
+        )
+        .map_err(|e| format!("invalid password hash parameters: {}", e))?;
+        Ok(PasswordHasher {
+            config,
+            argon2: Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        })
+    }
+
+    /// hash password with a fresh random salt
+    pub fn hash(&self, password: &str) -> Result<String, String> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(|e| format!("{}", e))?;
+        self.argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| format!("{}", e))
+    }
+
+    /// verify password against stored hash in constant time
+    /// returns a replacement hash if the stored hash parameters differ from the current config
+    pub fn verify(&self, password: &str, stored: &str) -> Result<Verified, String> {
+        let parsed = PasswordHash::new(stored).map_err(|e| format!("{}", e))?;
+        self.argon2
+            .verify_password(password.as_bytes(), &parsed)
+            .map_err(|_| "password does not match".to_owned())?;
+        if self.is_current(&parsed) {
+            return Ok(Verified::Current);
+        }
+        self.hash(password).map(Verified::Rehashed)
+    }
+
+    /// `hash` on the blocking thread pool, so hashing doesn't hold up the caller's thread
+    pub fn hash_in_pool(&self, password: String) -> impl Future<Output = Result<String, String>> {
+        let hasher = self.clone();
+        web::block(move || hasher.hash(&password)).map(pool_result)
+    }
+
+    /// `verify` on the blocking thread pool, so hashing doesn't hold up the caller's thread
+    pub fn verify_in_pool(
+        &self,
+        password: String,
+        stored: String,
+    ) -> impl Future<Output = Result<Verified, String>> {
+        let hasher = self.clone();
+        web::block(move || hasher.verify(&password, &stored)).map(pool_result)
+    }
+
+    /// check stored hash was made with the current algorithm and cost parameters
+    fn is_current(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != Algorithm::Argon2id.ident() {
+            return false;
+        }
+        match Params::try_from(hash) {
+            Ok(params) => {
+                params.m_cost() == self.config.memory_kib
+                    && params.t_cost() == self.config.iterations
+                    && params.p_cost() == self.config.parallelism
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+fn pool_result<T>(res: Result<T, BlockingError<String>>) -> Result<T, String> {
+    res.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => "password hashing was canceled".to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cheap(iterations: u32) -> PasswordHasher {
+        PasswordHasher::new(PasswordConfig {
+            memory_kib: 256,
+            iterations,
+            parallelism: 1,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_hash_and_verify() -> Result<(), String> {
+        let hasher = cheap(1);
+        let hash = hasher.hash("hunter2")?;
+        // plaintext is never part of the stored hash
+        assert!(!hash.contains("hunter2"));
+        assert!(hash.starts_with("$argon2id$"));
+        // salts differ between hashes of the same password
+        assert_ne!(hash, hasher.hash("hunter2")?);
+
+        assert_eq!(hasher.verify("hunter2", &hash)?, Verified::Current);
+        assert!(hasher.verify("hunter3", &hash).is_err());
+        assert!(hasher.verify("hunter2", "not a hash").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rehash_on_param_change() -> Result<(), String> {
+        let old = cheap(1);
+        let new = cheap(2);
+        let hash = old.hash("hunter2")?;
+        let rehashed = match new.verify("hunter2", &hash)? {
+            Verified::Rehashed(h) => h,
+            Verified::Current => panic!("expected rehash"),
+        };
+        assert_eq!(new.verify("hunter2", &rehashed)?, Verified::Current);
+        // wrong password never triggers a rehash
+        assert!(new.verify("nope", &hash).is_err());
+        Ok(())
+    }
+
+    #[actix_rt::test]
+    async fn test_hash_in_pool() -> Result<(), String> {
+        let hasher = cheap(1);
+        let hash = hasher.hash_in_pool("hunter2".into()).await?;
+        assert_eq!(
+            hasher
+                .verify_in_pool("hunter2".into(), hash.clone())
+                .await?,
+            Verified::Current
+        );
+        assert!(hasher.verify_in_pool("hunter3".into(), hash).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,182 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// JSON Patch (RFC 6902) operation
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+/// operations turning `before` into `after`
+/// objects are compared key by key, anything else that differs is replaced whole
+pub fn diff(before: &Value, after: &Value) -> Vec<PatchOp> {
+    let mut ops = Vec::new();
+    diff_at("", before, after, &mut ops);
+    ops
+}
+
+fn diff_at(path: &str, before: &Value, after: &Value, ops: &mut Vec<PatchOp>) {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => diff_objects(path, before, after, ops),
+        _ if before == after => {}
+        _ => ops.push(PatchOp::Replace {
+            path: path.to_owned(),
+            value: after.clone(),
+        }),
+    }
+}
+
+fn diff_objects(
+    path: &str,
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+    ops: &mut Vec<PatchOp>,
+) {
+    for key in before.keys().filter(|k| !after.contains_key(*k)) {
+        ops.push(PatchOp::Remove {
+            path: child_path(path, key),
+        });
+    }
+    for (key, value) in after {
+        let path = child_path(path, key);
+        match before.get(key) {
+            Some(old) => diff_at(&path, old, value, ops),
+            None => ops.push(PatchOp::Add {
+                path,
+                value: value.clone(),
+            }),
+        }
+    }
+}
+
+/// FNV-1a 64 bit hash of the compact JSON of `value`, with object keys sorted, as 16 hex digits
+/// cheap enough to send with every update, clients hash their own copy to detect drift
+/// hex as JSON numbers lose precision beyond 2^53 in most clients
+pub fn checksum(value: &Value) -> String {
+    let json = serde_json::to_string(value).expect("value serializes");
+    let hash = json.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// JSON Pointer to `key` of the object at `path`
+fn child_path(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// minimal patch application to check diffs round trip
+    fn apply(doc: &mut Value, ops: &[PatchOp]) {
+        for op in ops {
+            let (path, value) = match op {
+                PatchOp::Add { path, value } | PatchOp::Replace { path, value } => {
+                    (path, Some(value.clone()))
+                }
+                PatchOp::Remove { path } => (path, None),
+            };
+            if path.is_empty() {
+                *doc = value.unwrap();
+                continue;
+            }
+            let (parent, key) = path.rsplit_once('/').unwrap();
+            let key = key.replace("~1", "/").replace("~0", "~");
+            let parent = doc.pointer_mut(parent).unwrap().as_object_mut().unwrap();
+            match value {
+                Some(value) => parent.insert(key, value),
+                None => parent.remove(&key),
+            };
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = json!({
+            "version": 1,
+            "phase": "Init",
+            "host_user_id": null,
+            "board": {"size": 4, "map": {"0,1": "a", "2,2": "b"}},
+            "alive": ["a", "b"],
+            "a/b~": 1,
+        });
+        let after = json!({
+            "version": 2,
+            "phase": "Init",
+            "host_user_id": "a",
+            "board": {"size": 4, "map": {"0,1": "a", "3,3": "b"}},
+            "alive": ["a"],
+        });
+        let ops = diff(&before, &after);
+        assert_eq!(
+            ops,
+            vec![
+                PatchOp::Remove {
+                    path: "/a~1b~0".into()
+                },
+                PatchOp::Replace {
+                    path: "/alive".into(),
+                    value: json!(["a"])
+                },
+                PatchOp::Remove {
+                    path: "/board/map/2,2".into()
+                },
+                PatchOp::Add {
+                    path: "/board/map/3,3".into(),
+                    value: json!("b")
+                },
+                PatchOp::Replace {
+                    path: "/host_user_id".into(),
+                    value: json!("a")
+                },
+                PatchOp::Replace {
+                    path: "/version".into(),
+                    value: json!(2)
+                },
+            ]
+        );
+        let mut doc = before.clone();
+        apply(&mut doc, &ops);
+        assert_eq!(doc, after);
+
+        assert!(diff(&after, &after).is_empty());
+        // nothing to diff against replaces the whole document
+        assert_eq!(
+            diff(&Value::Null, &after),
+            vec![PatchOp::Replace {
+                path: "".into(),
+                value: after.clone()
+            }]
+        );
+        assert_eq!(
+            serde_json::to_value(&ops[0]).unwrap(),
+            json!({"op": "remove", "path": "/a~1b~0"})
+        );
+    }
+
+    #[test]
+    fn test_checksum() {
+        // FNV-1a reference values of the JSON text
+        assert_eq!(checksum(&json!(null)), "5b9bc4ba528108e4");
+        assert_eq!(checksum(&json!(1)), "af63ac4c86019afc");
+        // object keys are hashed in order, whatever order they were inserted in
+        let a: Value = serde_json::from_str(r#"{"b": [1, 2], "a": {"y": 1, "x": 2}}"#).unwrap();
+        let b: Value = serde_json::from_str(r#"{"a": {"x": 2, "y": 1}, "b": [1, 2]}"#).unwrap();
+        assert_eq!(checksum(&a), checksum(&b));
+        assert_ne!(
+            checksum(&a),
+            checksum(&json!({"a": {"x": 2, "y": 1}, "b": [2, 1]}))
+        );
+        // patched copies hash the same as the original
+        let mut doc = json!({"version": 1});
+        let ops = diff(&doc, &a);
+        apply(&mut doc, &ops);
+        assert_eq!(checksum(&doc), checksum(&a));
+    }
+}
@@ -0,0 +1,590 @@
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::io::Read;
+
+/// slash commands with a JSON or bare string argument, replies and pushes are `/command` strings
+pub const LEGACY_VERSION: u32 = 1;
+/// `{id, type, payload}` envelopes, each request gets one correlated ack or error
+pub const ENVELOPE_VERSION: u32 = 2;
+/// newest protocol version the server speaks
+pub const VERSION: u32 = ENVELOPE_VERSION;
+/// encodings the server can send and receive, most preferred first
+pub const ENCODINGS: &[&str] = &["json", "msgpack", "cbor"];
+/// optional features the server supports, clients opt in to them with `/hello`
+pub const FEATURES: &[&str] = &[DEFLATE, PREFERENTIAL_VOTES];
+/// frames in both directions are raw deflate compressed and sent as binary, whatever the encoding
+/// this is deflate at the application level, not RFC 7692 permessage-deflate: actix-web-actors
+/// can't negotiate websocket extensions, so clients inflate each binary frame themselves
+/// and each frame is compressed on its own, without a shared sliding window
+pub const DEFLATE: &str = "deflate";
+/// curse votes may rank several players, counted by preferential voting at the end of the turn
+pub const PREFERENTIAL_VOTES: &str = "preferential_votes";
+/// largest frame a client may send once inflated
+const MAX_INFLATED_LEN: u64 = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "ProtocolConfig::new", deny_unknown_fields)]
+pub struct ProtocolConfig {
+    /// clients that can't speak at least this version are refused
+    pub min_version: u32,
+}
+
+impl ProtocolConfig {
+    pub fn new() -> ProtocolConfig {
+        ProtocolConfig {
+            min_version: LEGACY_VERSION,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_version < LEGACY_VERSION || self.min_version > VERSION {
+            return Err(format!(
+                "min_version must be between {} and {}",
+                LEGACY_VERSION, VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// how frames are encoded on the websocket
+/// JSON is sent as text, binary encodings carry v2 envelopes in binary frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MsgPack),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        !matches!(self, Encoding::Json)
+    }
+
+    /// encode envelope for a binary frame
+    pub fn encode(&self, envelope: &Envelope) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(envelope).map_err(|e| e.to_string()),
+            Encoding::MsgPack => rmp_serde::to_vec_named(envelope).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut bytes = vec![];
+                ciborium::ser::into_writer(envelope, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// decode a binary frame into the JSON value it represents
+    pub fn decode(&self, bytes: &[u8]) -> Result<Value, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// compress a frame for a session with `deflate` enabled
+pub fn deflate(bytes: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![];
+    DeflateEncoder::new(bytes, Compression::default())
+        .read_to_end(&mut compressed)
+        .expect("deflating from memory");
+    compressed
+}
+
+/// decompress a frame from a session with `deflate` enabled
+pub fn inflate(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut inflated = vec![];
+    DeflateDecoder::new(bytes)
+        .take(MAX_INFLATED_LEN + 1)
+        .read_to_end(&mut inflated)
+        .map_err(|e| e.to_string())?;
+    if inflated.len() as u64 > MAX_INFLATED_LEN {
+        return Err(format!(
+            "frame inflates to more than {} bytes",
+            MAX_INFLATED_LEN
+        ));
+    }
+    Ok(inflated)
+}
+
+/// reason a request was rejected before reaching the relay server, serialized as `{code, details}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
+pub enum ProtocolError {
+    /// request or its payload couldn't be read
+    BadRequest(String),
+    UnknownCommand(String),
+    NotLoggedIn,
+    UnsupportedVersion {
+        version: u32,
+        min_version: u32,
+    },
+    /// none of the client's encodings are among the server's `encodings`
+    UnsupportedEncoding {
+        encodings: Vec<String>,
+    },
+    /// too many commands of `class`, retry after `retry_after_ms`
+    RateLimited {
+        class: String,
+        retry_after_ms: u64,
+    },
+    /// session kept sending throttled commands and is closed
+    Flooding,
+    /// server failed to handle the request
+    Internal(String),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadRequest(e) => write!(f, "{}", e),
+            ProtocolError::UnknownCommand(kind) => write!(f, "unknown command type {:?}", kind),
+            ProtocolError::NotLoggedIn => write!(f, "user not logged in"),
+            ProtocolError::UnsupportedVersion {
+                version,
+                min_version,
+            } => write!(
+                f,
+                "protocol version {} is no longer supported, upgrade to version {} or later",
+                version, min_version
+            ),
+            ProtocolError::UnsupportedEncoding { encodings } => {
+                write!(f, "no supported encoding, server supports {:?}", encodings)
+            }
+            ProtocolError::RateLimited {
+                class,
+                retry_after_ms,
+            } => write!(
+                f,
+                "too many {} commands, retry in {} ms",
+                class, retry_after_ms
+            ),
+            ProtocolError::Flooding => write!(f, "rate limit exceeded, closing session"),
+            ProtocolError::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// `/hello` handshake sent by the client before its other commands
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Hello {
+    /// newest version the client speaks
+    pub version: u32,
+    /// encodings the client accepts, most preferred first
+    #[serde(default = "Hello::default_encodings")]
+    pub encodings: Vec<String>,
+    /// optional features the client wants enabled
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// server's answer to `/hello`, the session speaks the negotiated version from then on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HelloReply {
+    /// negotiated version, the older of the client's and the server's
+    pub version: u32,
+    pub min_version: u32,
+    pub max_version: u32,
+    /// negotiated encoding
+    pub encoding: String,
+    pub encodings: Vec<String>,
+    /// requested features the server supports, the rest stay disabled
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    fn default_encodings() -> Vec<String> {
+        vec!["json".into()]
+    }
+
+    /// agree on version, encoding and features, clients older than `min_version` are refused
+    /// clients newer than the server are downgraded to the server's version
+    /// binary frames carry envelopes so legacy clients are limited to JSON without `deflate`
+    pub fn negotiate(&self, config: &ProtocolConfig) -> Result<HelloReply, ProtocolError> {
+        let version = self.version.min(VERSION);
+        if version < config.min_version {
+            return Err(ProtocolError::UnsupportedVersion {
+                version: self.version,
+                min_version: config.min_version,
+            });
+        }
+        let encoding = self
+            .encodings
+            .iter()
+            .find(|e| match Encoding::parse(e) {
+                Some(encoding) => version >= ENVELOPE_VERSION || !encoding.is_binary(),
+                None => false,
+            })
+            .ok_or_else(|| ProtocolError::UnsupportedEncoding {
+                encodings: ENCODINGS.iter().map(|e| e.to_string()).collect(),
+            })?;
+        Ok(HelloReply {
+            version,
+            min_version: config.min_version,
+            max_version: VERSION,
+            encoding: encoding.clone(),
+            encodings: ENCODINGS.iter().map(|e| e.to_string()).collect(),
+            features: self
+                .features
+                .iter()
+                .filter(|f| FEATURES.contains(&f.as_str()))
+                .filter(|f| *f != DEFLATE || version >= ENVELOPE_VERSION)
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+/// client request from a v2 envelope or a legacy slash command
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    /// echoed in the ack or error, `None` for legacy commands
+    pub id: Option<Value>,
+    /// command name without the leading slash
+    pub kind: String,
+    pub payload: Value,
+}
+
+#[derive(Deserialize)]
+struct RawRequest {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    payload: Value,
+}
+
+impl Request {
+    /// parse `/command argument`, the argument is kept as a string payload
+    pub fn from_legacy(text: &str) -> Result<Request, ProtocolError> {
+        let text = text.trim();
+        let (cmd, arg) = text.split_once(' ').unwrap_or((text, ""));
+        let kind = cmd
+            .strip_prefix('/')
+            .ok_or_else(|| ProtocolError::UnknownCommand(text.to_owned()))?;
+        Ok(Request {
+            id: None,
+            kind: kind.to_owned(),
+            payload: Value::String(arg.to_owned()),
+        })
+    }
+
+    /// parse `{id, type, payload}` envelope
+    /// on error returns the request ID, if one could be read, so the error can still be correlated
+    pub fn from_envelope(text: &str) -> Result<Request, (Option<Value>, ProtocolError)> {
+        let value: Value = serde_json::from_str(text)
+            .map_err(|e| (None, ProtocolError::BadRequest(e.to_string())))?;
+        Request::from_value(value)
+    }
+
+    /// parse envelope decoded from a binary frame, see `from_envelope`
+    pub fn from_value(value: Value) -> Result<Request, (Option<Value>, ProtocolError)> {
+        let id = match value.get("id") {
+            Some(id) if !id.is_null() => id.clone(),
+            _ => {
+                return Err((
+                    None,
+                    ProtocolError::BadRequest("request id is required".into()),
+                ))
+            }
+        };
+        let raw: RawRequest = serde_json::from_value(value)
+            .map_err(|e| (Some(id.clone()), ProtocolError::BadRequest(e.to_string())))?;
+        Ok(Request {
+            id: Some(id),
+            kind: raw.kind,
+            payload: raw.payload,
+        })
+    }
+
+    /// deserialize payload
+    /// string payloads, as sent by legacy commands, hold either JSON or a bare string
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T, ProtocolError> {
+        match &self.payload {
+            Value::String(s) => serde_json::from_str(s)
+                .or_else(|e| serde_json::from_value(Value::String(s.clone())).map_err(|_| e)),
+            payload => serde_json::from_value(payload.clone()),
+        }
+        .map_err(|e| ProtocolError::BadRequest(format!("{:?}", e)))
+    }
+}
+
+/// error reply, clients react to the error's `code` and `details`, `message` is prose for display
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorPayload {
+    pub context: String,
+    pub message: String,
+    /// `{code, details}` of the error
+    #[serde(flatten)]
+    pub error: Value,
+}
+
+impl ErrorPayload {
+    pub fn new<E: Serialize + Display>(context: &str, e: &E) -> ErrorPayload {
+        let error = match serde_json::to_value(e) {
+            Ok(error @ Value::Object(_)) => error,
+            _ => json!({ "code": "internal" }),
+        };
+        ErrorPayload {
+            context: context.to_owned(),
+            message: e.to_string(),
+            error,
+        }
+    }
+}
+
+/// frame sent to a v2 client
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Envelope {
+    /// request succeeded
+    Ack { id: Value, payload: Value },
+    /// request failed, `id` is null if the request couldn't be read
+    Error { id: Value, payload: ErrorPayload },
+    /// server push not caused by a request
+    /// pushes to a user carry their sequence number, for `resume`, and when the server sent them
+    Event {
+        event: String,
+        payload: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sent_unix_ms: Option<u64>,
+    },
+}
+
+impl Envelope {
+    pub fn error(id: Option<Value>, payload: ErrorPayload) -> Envelope {
+        Envelope::Error {
+            id: id.unwrap_or(Value::Null),
+            payload,
+        }
+    }
+
+    /// convert a legacy `/event argument` push, the argument becomes JSON payload if it parses
+    pub fn from_legacy(text: &str) -> Envelope {
+        let (cmd, arg) = text.split_once(' ').unwrap_or((text, ""));
+        let payload = match arg {
+            "" => Value::Null,
+            arg => serde_json::from_str(arg).unwrap_or_else(|_| Value::String(arg.to_owned())),
+        };
+        Envelope::Event {
+            event: cmd.trim_start_matches('/').to_owned(),
+            payload,
+            seq: None,
+            sent_unix_ms: None,
+        }
+    }
+
+    /// stamp an event with its sequence number and send time, other envelopes are unchanged
+    pub fn sequenced(mut self, seq: Option<u64>, sent_unix_ms: u64) -> Envelope {
+        if let Envelope::Event {
+            seq: s,
+            sent_unix_ms: t,
+            ..
+        } = &mut self
+        {
+            *s = seq;
+            *t = Some(sent_unix_ms);
+        }
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("envelope serializes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::election::ElectionError;
+    use crate::game::GameError;
+    use crate::relay_server::RelayError;
+
+    #[test]
+    fn test_parse_requests() {
+        let req = Request::from_legacy("/join_game g1").unwrap();
+        assert_eq!(req.kind, "join_game");
+        assert_eq!(req.payload::<String>(), Ok("g1".into()));
+        let req = Request::from_legacy("/user_status").unwrap();
+        assert_eq!(req.kind, "user_status");
+        assert!(Request::from_legacy("user_status").is_err());
+
+        let req = Request::from_envelope(r#"{"id": 7, "type": "join_game", "payload": "g1"}"#);
+        let req = req.unwrap();
+        assert_eq!(req.id, Some(json!(7)));
+        assert_eq!(req.payload::<String>(), Ok("g1".into()));
+        // legacy JSON arguments and envelope payloads deserialize the same
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Args {
+            game_id: String,
+        }
+        let legacy = Request::from_legacy(r#"/start_game {"game_id": "g1"}"#).unwrap();
+        let envelope =
+            Request::from_envelope(r#"{"id": "a", "type": "x", "payload": {"game_id": "g1"}}"#);
+        assert_eq!(
+            legacy.payload::<Args>(),
+            envelope.unwrap().payload::<Args>()
+        );
+
+        assert_eq!(
+            Request::from_envelope(r#"{"id": "a"}"#).map_err(|(id, _)| id),
+            Err(Some(json!("a")))
+        );
+        assert_eq!(
+            Request::from_envelope(r#"{"type": "x"}"#).map_err(|(id, _)| id),
+            Err(None)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_hello() {
+        let config = ProtocolConfig::new();
+        let hello: Hello = serde_json::from_value(json!({"version": 9})).unwrap();
+        let reply = hello.negotiate(&config).unwrap();
+        // newer clients are downgraded
+        assert_eq!(reply.version, VERSION);
+        assert_eq!(reply.encoding, "json");
+
+        let hello: Hello = serde_json::from_value(json!({
+            "version": 1,
+            "encodings": ["cbor", "json"],
+            "features": ["fog_of_war", "deflate"],
+        }))
+        .unwrap();
+        let reply = hello.negotiate(&config).unwrap();
+        assert_eq!(reply.version, LEGACY_VERSION);
+        assert_eq!(reply.encoding, "json");
+        assert!(reply.features.is_empty());
+
+        // too old for the server
+        let strict = ProtocolConfig {
+            min_version: ENVELOPE_VERSION,
+        };
+        assert!(hello.negotiate(&strict).is_err());
+        let hello: Hello =
+            serde_json::from_value(json!({"version": 2, "encodings": ["xml"]})).unwrap();
+        assert!(hello.negotiate(&config).is_err());
+
+        // unknown features are left out
+        let hello: Hello = serde_json::from_value(json!({
+            "version": 2,
+            "features": ["fog_of_war", "preferential_votes"],
+        }))
+        .unwrap();
+        let reply = hello.negotiate(&config).unwrap();
+        assert_eq!(reply.features, vec![PREFERENTIAL_VOTES.to_owned()]);
+    }
+
+    #[test]
+    fn test_binary_encodings() {
+        let hello: Hello =
+            serde_json::from_value(json!({"version": 2, "encodings": ["cbor", "json"]})).unwrap();
+        assert_eq!(
+            hello.negotiate(&ProtocolConfig::new()).unwrap().encoding,
+            "cbor"
+        );
+
+        let envelope = Envelope::from_legacy(r#"/player_joined {"user_id": "a", "lives": 3}"#);
+        let request = json!({"id": 1, "type": "join_game", "payload": {"game_id": "g1"}});
+        for encoding in &[Encoding::MsgPack, Encoding::Cbor] {
+            let bytes = encoding.encode(&envelope).unwrap();
+            assert_eq!(
+                encoding.decode(&bytes).unwrap(),
+                serde_json::to_value(&envelope).unwrap()
+            );
+            let bytes = match encoding {
+                Encoding::MsgPack => rmp_serde::to_vec_named(&request).unwrap(),
+                _ => {
+                    let mut bytes = vec![];
+                    ciborium::ser::into_writer(&request, &mut bytes).unwrap();
+                    bytes
+                }
+            };
+            let req = Request::from_value(encoding.decode(&bytes).unwrap()).unwrap();
+            assert_eq!(req.kind, "join_game");
+            assert_eq!(req.id, Some(json!(1)));
+        }
+        assert!(Encoding::MsgPack.decode(b"\xc1").is_err());
+    }
+
+    #[test]
+    fn test_deflate() {
+        let hello: Hello =
+            serde_json::from_value(json!({"version": 2, "features": ["deflate"]})).unwrap();
+        let reply = hello.negotiate(&ProtocolConfig::new()).unwrap();
+        assert_eq!(reply.features, vec![DEFLATE.to_owned()]);
+        assert_eq!(reply.encoding, "json");
+
+        let json = Envelope::from_legacy(&format!("/alert {}", "a".repeat(1000))).to_json();
+        let compressed = deflate(json.as_bytes());
+        assert!(compressed.len() < json.len() / 10);
+        assert_eq!(inflate(&compressed).unwrap(), json.as_bytes());
+        assert!(inflate(b"not deflate").is_err());
+        // frames inflating past the limit are refused
+        let bomb = deflate(&vec![0; MAX_INFLATED_LEN as usize + 1]);
+        assert!(inflate(&bomb).is_err());
+    }
+
+    #[test]
+    fn test_envelope_json() {
+        assert_eq!(
+            serde_json::to_value(Envelope::from_legacy(r#"/player_joined {"user_id": "a"}"#))
+                .unwrap(),
+            json!({"type": "event", "event": "player_joined", "payload": {"user_id": "a"}})
+        );
+        assert_eq!(
+            serde_json::to_value(Envelope::from_legacy("/alert new session")).unwrap(),
+            json!({"type": "event", "event": "alert", "payload": "new session"})
+        );
+        assert_eq!(
+            serde_json::to_value(Envelope::from_legacy("/alert hi").sequenced(Some(4), 1000))
+                .unwrap(),
+            json!({"type": "event", "event": "alert", "payload": "hi",
+                "seq": 4, "sent_unix_ms": 1000})
+        );
+        let payload = ErrorPayload::new("session", &ProtocolError::NotLoggedIn);
+        assert_eq!(
+            serde_json::to_value(Envelope::error(Some(json!(3)), payload)).unwrap(),
+            json!({"type": "error", "id": 3, "payload": {
+                "context": "session", "message": "user not logged in", "code": "not_logged_in"}})
+        );
+        let e = ProtocolError::UnsupportedVersion {
+            version: 1,
+            min_version: 2,
+        };
+        assert_eq!(
+            serde_json::to_value(ErrorPayload::new("hello", &e)).unwrap()["details"],
+            json!({"version": 1, "min_version": 2})
+        );
+        // wrapped errors keep the code of the layer that raised them
+        let e = RelayError::from(GameError::InsufficientActionPoints {
+            required: 3,
+            available: 1,
+        });
+        assert_eq!(
+            serde_json::to_value(ErrorPayload::new("player_action", &e)).unwrap(),
+            json!({"context": "player_action", "message": "3 action points required",
+                "code": "insufficient_action_points", "details": {"required": 3, "available": 1}})
+        );
+        let e = RelayError::from(GameError::from(ElectionError::DuplicatePreferences));
+        assert_eq!(
+            serde_json::to_value(ErrorPayload::new("player_action", &e)).unwrap()["code"],
+            json!("duplicate_preferences")
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::{metrics::Metrics, protocol::ProtocolError};
+
+/// token bucket size and refill rate
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    /// commands allowed back to back
+    pub burst: u32,
+    /// tokens refilled per second
+    pub per_sec: f64,
+}
+
+impl Budget {
+    fn validate(&self) -> Result<(), String> {
+        if self.burst == 0 || !self.per_sec.is_finite() || self.per_sec <= 0.0 {
+            return Err("burst and per_sec must be greater than 0".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "RateLimitConfig::new", deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub auth: Budget,
+    pub lobby: Budget,
+    pub action: Budget,
+    pub query: Budget,
+    /// every throttled command takes a strike, sessions out of strikes are disconnected
+    pub strikes: Budget,
+}
+
+impl RateLimitConfig {
+    pub fn new() -> RateLimitConfig {
+        RateLimitConfig {
+            auth: Budget {
+                burst: 5,
+                per_sec: 0.5,
+            },
+            lobby: Budget {
+                burst: 10,
+                per_sec: 2.0,
+            },
+            action: Budget {
+                burst: 10,
+                per_sec: 5.0,
+            },
+            query: Budget {
+                burst: 20,
+                per_sec: 5.0,
+            },
+            strikes: Budget {
+                burst: 20,
+                per_sec: 0.5,
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let budgets = [
+            ("auth", &self.auth),
+            ("lobby", &self.lobby),
+            ("action", &self.action),
+            ("query", &self.query),
+            ("strikes", &self.strikes),
+        ];
+        for (name, budget) in budgets {
+            budget.validate().map_err(|e| format!("{}: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    fn budget(&self, class: CommandClass) -> &Budget {
+        match class {
+            CommandClass::Auth => &self.auth,
+            CommandClass::Lobby => &self.lobby,
+            CommandClass::Action => &self.action,
+            CommandClass::Query => &self.query,
+        }
+    }
+}
+
+/// commands sharing a budget
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandClass {
+    /// logins and token changes, expensive password hashing
+    Auth,
+    /// hosting, joining, configuring and starting games
+    Lobby,
+    Action,
+    /// everything else, including unknown commands
+    Query,
+}
+
+impl CommandClass {
+    pub const ALL: [CommandClass; 4] = [
+        CommandClass::Auth,
+        CommandClass::Lobby,
+        CommandClass::Action,
+        CommandClass::Query,
+    ];
+
+    pub fn of(kind: &str) -> CommandClass {
+        match kind {
+            "login" | "register" | "verify" | "refresh_token" | "revoke_token"
+            | "revoke_all_tokens" => CommandClass::Auth,
+            "host_game" | "join_game" | "conf_game" | "start_game" | "find_match"
+            | "cancel_match" | "create_invite" => CommandClass::Lobby,
+            "player_action" => CommandClass::Action,
+            _ => CommandClass::Query,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandClass::Auth => "auth",
+            CommandClass::Lobby => "lobby",
+            CommandClass::Action => "action",
+            CommandClass::Query => "query",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(budget: &Budget, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: f64::from(budget.burst),
+            updated: now,
+        }
+    }
+
+    /// take a token, or error with the time until one is refilled
+    fn take(&mut self, budget: &Budget, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_sec).min(f64::from(budget.burst));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / budget.per_sec,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    /// refuse the command, the class has a token again after `retry_after`
+    Throttle {
+        retry_after: Duration,
+    },
+    /// session keeps sending throttled commands and should be closed
+    Disconnect,
+}
+
+/// command budgets of a single session
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: [TokenBucket; 4],
+    strikes: TokenBucket,
+    /// a command of this session has been throttled
+    throttled: bool,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, now: Instant) -> RateLimiter {
+        let buckets = CommandClass::ALL.map(|class| TokenBucket::full(config.budget(class), now));
+        RateLimiter {
+            strikes: TokenBucket::full(&config.strikes, now),
+            buckets,
+            config,
+            throttled: false,
+        }
+    }
+
+    pub fn check(&mut self, class: CommandClass, now: Instant) -> Verdict {
+        let budget = self.config.budget(class);
+        let bucket = &mut self.buckets[class as usize];
+        match bucket.take(budget, now) {
+            Ok(()) => Verdict::Allow,
+            Err(retry_after) => match self.strikes.take(&self.config.strikes, now) {
+                Ok(()) => Verdict::Throttle { retry_after },
+                Err(_) => Verdict::Disconnect,
+            },
+        }
+    }
+
+    /// check command against its class budget, counting refusals in `metrics`
+    /// `ProtocolError::Flooding` means the session should be closed
+    pub fn admit(
+        &mut self,
+        kind: &str,
+        now: Instant,
+        metrics: &Metrics,
+    ) -> Result<(), ProtocolError> {
+        let class = CommandClass::of(kind);
+        match self.check(class, now) {
+            Verdict::Allow => Ok(()),
+            Verdict::Throttle { retry_after } => {
+                metrics.command_throttled(class);
+                if !self.throttled {
+                    self.throttled = true;
+                    metrics.session_throttled();
+                }
+                Err(ProtocolError::RateLimited {
+                    class: class.name().into(),
+                    retry_after_ms: retry_after.as_millis() as u64,
+                })
+            }
+            Verdict::Disconnect => {
+                metrics.command_throttled(class);
+                metrics.rate_limit_disconnect();
+                Err(ProtocolError::Flooding)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(now: Instant) -> RateLimiter {
+        let mut config = RateLimitConfig::new();
+        config.action = Budget {
+            burst: 2,
+            per_sec: 1.0,
+        };
+        config.strikes = Budget {
+            burst: 2,
+            per_sec: 0.1,
+        };
+        RateLimiter::new(config, now)
+    }
+
+    #[test]
+    fn test_throttle_and_refill() {
+        let start = Instant::now();
+        let mut l = limiter(start);
+        assert_eq!(l.check(CommandClass::Action, start), Verdict::Allow);
+        assert_eq!(l.check(CommandClass::Action, start), Verdict::Allow);
+        assert_eq!(
+            l.check(CommandClass::Action, start),
+            Verdict::Throttle {
+                retry_after: Duration::from_secs(1)
+            }
+        );
+        // classes have separate budgets
+        assert_eq!(l.check(CommandClass::Lobby, start), Verdict::Allow);
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(l.check(CommandClass::Action, later), Verdict::Allow);
+        assert_eq!(
+            l.check(CommandClass::Action, later),
+            Verdict::Throttle {
+                retry_after: Duration::from_millis(500)
+            }
+        );
+        // refill stops at the burst size
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..2 {
+            assert_eq!(l.check(CommandClass::Action, much_later), Verdict::Allow);
+        }
+        assert!(matches!(
+            l.check(CommandClass::Action, much_later),
+            Verdict::Throttle { .. }
+        ));
+    }
+
+    #[test]
+    fn test_disconnect_when_out_of_strikes() {
+        let now = Instant::now();
+        let mut l = limiter(now);
+        for _ in 0..2 {
+            assert_eq!(l.check(CommandClass::Action, now), Verdict::Allow);
+        }
+        for _ in 0..2 {
+            assert!(matches!(
+                l.check(CommandClass::Action, now),
+                Verdict::Throttle { .. }
+            ));
+        }
+        assert_eq!(l.check(CommandClass::Action, now), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn test_classes() {
+        assert_eq!(CommandClass::of("login"), CommandClass::Auth);
+        assert_eq!(CommandClass::of("conf_game"), CommandClass::Lobby);
+        assert_eq!(CommandClass::of("player_action"), CommandClass::Action);
+        assert_eq!(CommandClass::of("nope"), CommandClass::Query);
+        assert!(RateLimitConfig::new().validate().is_ok());
+        let mut config = RateLimitConfig::new();
+        config.query.per_sec = 0.0;
+        assert!(config.validate().is_err());
+    }
+}
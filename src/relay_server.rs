@@ -1,33 +1,136 @@
-use crate::common::gen_rng_string;
+use crate::account::{check_password_strength, normalize_user_id, validate_user_id};
 use crate::common::ActionPointUpdate;
 use crate::common::ConfigGameOp;
 use crate::common::Fail;
+use crate::common::MatchStatus;
 use crate::common::MsgResult;
 use crate::common::SuccessResult;
+use crate::common::UserProfileResult;
 use crate::common::UserStatusResult;
+use crate::common::Visibility;
 use crate::game::ActionType;
 use crate::game::Game;
+use crate::game::GameConfig;
+use crate::game::GameError;
+use crate::game::GameFilter;
+use crate::game::GameSummary;
 use crate::game::InsertPlayerResult;
 use crate::game::Player;
 use crate::game::PlayerActionResult;
+use crate::game::PlayerGameState;
 use crate::game::Pos;
-use crate::game::BOARD_SIZE;
+use crate::invite::{Invite, InviteStore};
+use crate::lobby::{self, Lobby};
+use crate::login_guard::LoginGuard;
+use crate::matchmaking::{MatchPrefs, MatchQueue, MatchmakingConfig};
+use crate::password::{PasswordHasher, Verified};
+use crate::token::{
+    gen_token, now_unix, now_unix_ms, SessionToken, TokenRejection, TokenStore, TOKEN_TTL_SECS,
+};
+use crate::user_store::{User, UserStore};
 use actix::prelude::*;
 use log::debug;
 use rand::prelude::ThreadRng;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
 use std::time::Duration;
 
 /// server sends this message to session
-#[derive(Message, Debug)]
+/// messages to a user are numbered so a reconnecting session can `Resume` after the last it saw
+#[derive(Message, Debug, Clone)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message {
+    pub text: String,
+    /// per user sequence number, `None` for messages to a single session which aren't replayed
+    pub seq: Option<u64>,
+    pub sent_unix_ms: u64,
+}
 
-#[derive(Debug, Clone)]
-pub struct User {
-    pub user_id: String,
-    pub password: String,
+impl Message {
+    /// unnumbered message to a single session
+    pub fn new(text: String) -> Message {
+        Message {
+            text,
+            seq: None,
+            sent_unix_ms: now_unix_ms(),
+        }
+    }
+}
+
+/// reason a game request was rejected, serialized as `{code, details}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code", content = "details", rename_all = "snake_case")]
+pub enum RelayError {
+    GameExists {
+        game_id: String,
+    },
+    /// user must finish `game_id` before joining or hosting another
+    AlreadyInGame {
+        game_id: String,
+    },
+    GameNotFound {
+        game_id: String,
+    },
+    UserNotFound {
+        user_id: String,
+    },
+    NotHost {
+        game_id: String,
+    },
+    NotInGame {
+        game_id: String,
+    },
+    InviteExpired {
+        code: String,
+    },
+    /// too many wrong passwords for `game_id` from the user or their IP
+    JoinLocked {
+        game_id: String,
+        retry_after_secs: u64,
+    },
+    /// messages after `last_seq` are no longer kept, the client must fetch the full state
+    ReplayUnavailable {
+        last_seq: u64,
+        oldest_seq: u64,
+    },
+    /// reply could not be serialized
+    Internal(String),
+    #[serde(untagged)]
+    Game(GameError),
+}
+
+impl Display for RelayError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            RelayError::GameExists { game_id } => write!(f, "{} exists", game_id),
+            RelayError::AlreadyInGame { .. } => write!(f, "already in another game"),
+            RelayError::GameNotFound { .. } => write!(f, "game not found"),
+            RelayError::UserNotFound { .. } => write!(f, "user not found"),
+            RelayError::NotHost { .. } => write!(f, "only host can change game"),
+            RelayError::NotInGame { game_id } => write!(f, "user not in game {}", game_id),
+            RelayError::InviteExpired { .. } => write!(f, "invite expired"),
+            RelayError::JoinLocked {
+                retry_after_secs, ..
+            } => write!(
+                f,
+                "too many wrong game passwords, retry in {} seconds",
+                retry_after_secs
+            ),
+            RelayError::ReplayUnavailable { last_seq, .. } => {
+                write!(f, "messages after {} are no longer available", last_seq)
+            }
+            RelayError::Internal(e) => write!(f, "{}", e),
+            RelayError::Game(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<GameError> for RelayError {
+    fn from(e: GameError) -> RelayError {
+        RelayError::Game(e)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -37,34 +140,134 @@ pub enum ConnectResult {
 }
 
 /// New client session with relay server is created
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Connect {
-    pub user: User,
+    pub user_id: String,
+    pub password: String,
     pub addr: Option<Recipient<Message>>,
+    /// IP address of the client, used to limit failed attempts
+    pub ip: Option<String>,
 }
 impl actix::Message for Connect {
     type Result = ConnectResult;
 }
 
+// password is left out so it never reaches the logs
+impl std::fmt::Debug for Connect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Connect")
+            .field("user_id", &self.user_id)
+            .field("addr", &self.addr)
+            .field("ip", &self.ip)
+            .finish()
+    }
+}
+
+/// Create a new user account, fails if user ID is taken or credentials are invalid
+/// logs the new user in the same way as `Connect`
+#[derive(Clone)]
+pub struct Register {
+    pub user_id: String,
+    pub password: String,
+    pub addr: Option<Recipient<Message>>,
+}
+impl actix::Message for Register {
+    type Result = ConnectResult;
+}
+
+// password is left out so it never reaches the logs
+impl std::fmt::Debug for Register {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Register")
+            .field("user_id", &self.user_id)
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+/// credential proving a user has already authenticated over HTTP
+#[derive(Clone)]
+pub enum Credential {
+    /// user ID and session token stored in the HTTP session at login
+    SessionUser { user_id: String, token: String },
+    /// session token presented as a bearer token
+    BearerToken(String),
+}
+
+// tokens are left out so they never reach the logs
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Credential::SessionUser { user_id, .. } => write!(f, "SessionUser({:?})", user_id),
+            Credential::BearerToken(_) => write!(f, "BearerToken"),
+        }
+    }
+}
+
+/// check a credential would attach without attaching anything, returns its user ID
+/// lets upgrades be refused before they start
+#[derive(Message, Debug)]
+#[rtype(result = "Result<String, TokenRejection>")]
+pub struct CheckCredential(pub Credential);
+
+/// Attach a new client session to a user without their password
+#[derive(Clone, Debug)]
+pub struct Attach {
+    pub credential: Credential,
+    pub addr: Recipient<Message>,
+}
+impl actix::Message for Attach {
+    type Result = ConnectResult;
+}
+
 /// verify that the sender's session is associated with their user on the relay_server
+/// returns the token's user ID so a new session can adopt it
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<String, TokenRejection>")]
 pub struct VerifySession {
     pub user_id: Option<String>,
     pub addr: Recipient<Message>,
     pub token: String,
 }
 
-/// Session is disconnected
+/// exchange a valid session token for a new one with a later expiry
+#[derive(Message, Debug)]
+#[rtype(result = "Result<SessionToken, TokenRejection>")]
+pub struct RefreshToken {
+    pub user_id: String,
+    pub token: String,
+}
+
+/// revoke a session token of user, or every token of user if `token` is `None`
+/// sessions verified with a revoked token are logged out
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct RevokeToken {
+    pub user_id: String,
+    pub token: Option<String>,
+}
+
+/// resend messages the user was sent after `last_seq` to a reconnected session
+/// returns the number of messages resent
+#[derive(Message, Debug)]
+#[rtype(result = "Result<usize, RelayError>")]
+pub struct Resume {
+    pub user_id: String,
+    pub addr: Recipient<Message>,
+    pub last_seq: u64,
+}
+
+/// Session is disconnected, other sessions of the user stay connected
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub user_id: String,
+    pub addr: Recipient<Message>,
 }
 
 /// Host a game, if already exists throw error
 #[derive(Message, Clone, Debug)]
-#[rtype(result = "Result<(), String>")]
+#[rtype(result = "Result<(), RelayError>")]
 pub struct HostGame {
     pub host_user_id: String,
     pub game_id: String,
@@ -72,16 +275,33 @@ pub struct HostGame {
 
 /// Join game, if non-existant throw error
 #[derive(Message, Clone, Debug)]
-#[rtype(result = "Result<(), String>")]
+#[rtype(result = "Result<(), RelayError>")]
 pub struct JoinGame {
     /// user id of joiner
     pub user_id: String,
+    /// game ID, or an invite code for the game
     pub game_id: String,
+    /// needed for `Password` visible games
+    pub password: Option<String>,
+    /// IP address of the client, used to limit wrong passwords
+    pub ip: Option<String>,
+}
+
+/// invite code for a game, only its host can create them
+/// players that join with it are reported to the host
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Invite, RelayError>")]
+pub struct CreateInvite {
+    pub user_id: String,
+    pub game_id: String,
+    /// see `InviteStore::mint`
+    pub ttl_secs: Option<u64>,
+    pub single_use: bool,
 }
 
 /// Edit game, if already started, non-existant - throw error
 #[derive(Message, Debug, Clone, Deserialize)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<(), RelayError>")]
 pub struct ConfigGame {
     pub game_id: String,
     pub user_id: String,
@@ -90,22 +310,82 @@ pub struct ConfigGame {
 
 /// Start game, if non-existant throw error
 #[derive(Message, Clone, Debug)]
-#[rtype(result = "Result<(), String>")]
+#[rtype(result = "Result<(), RelayError>")]
 pub struct StartGame {
     /// user id of joiner
     pub user_id: String,
     pub game_id: String,
 }
 
+/// queue user for a game with players of compatible prefs, see `MatchQueue`
+/// matched users are put in a new game that starts straight away
+#[derive(Message, Debug)]
+#[rtype(result = "Result<MatchStatus, RelayError>")]
+pub struct FindMatch {
+    pub user_id: String,
+    pub prefs: MatchPrefs,
+}
+
+/// leave the matchmaking queue, returns false if the user wasn't queued
+#[derive(Message, Debug)]
+#[rtype(result = "bool")]
+pub struct CancelMatch {
+    pub user_id: String,
+}
+
 /// check if user has a game in progress they should know about
+/// status is also sent to every session of the user
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
+#[rtype(result = "UserStatusResult")]
 pub struct UserStatus {
     pub user_id: String,
 }
 
+/// listed games matching filter, sorted by ID, for lobby browsers
 #[derive(Message, Debug)]
-#[rtype(result = "()")]
+#[rtype(result = "Vec<GameSummary>")]
+pub struct ListGames {
+    pub filter: GameFilter,
+}
+
+/// listed games matching filter, the session is then sent changes to the list
+/// see `Lobby`
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<GameSummary>")]
+pub struct WatchLobby {
+    pub addr: Recipient<Message>,
+    pub filter: GameFilter,
+}
+
+/// game as its players see it, without anyone's private data
+/// games that aren't listed are only found by their players
+#[derive(Message, Debug)]
+#[rtype(result = "Result<Value, RelayError>")]
+pub struct PublicGame {
+    pub game_id: String,
+    /// user asking, `None` if anonymous
+    pub user_id: Option<String>,
+}
+
+/// public profile of a user, nothing is pushed to the user's sessions
+/// the user's game is left out unless it is listed, so profiles don't leak unlisted game IDs
+#[derive(Message, Debug)]
+#[rtype(result = "Result<UserProfileResult, RelayError>")]
+pub struct UserProfile {
+    pub user_id: String,
+}
+
+/// game as seen by one of its players, for clients that missed updates
+/// nothing is pushed to the user's sessions
+#[derive(Message, Debug)]
+#[rtype(result = "Result<PlayerGameState, RelayError>")]
+pub struct GameState {
+    pub user_id: String,
+    pub game_id: String,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "Result<(), RelayError>")]
 pub struct PlayerActionRequest {
     pub user_id: String,
     pub game_id: String,
@@ -119,7 +399,7 @@ pub struct SpawnTileHeart {
 }
 
 #[derive(Message, Clone, Debug)]
-#[rtype(result = "Result<(), String>")]
+#[rtype(result = "Result<(), RelayError>")]
 pub struct Replenish {
     pub game_id: String,
 }
@@ -129,65 +409,357 @@ pub struct Replenish {
 /// relays game events to users
 /// handles dead sessions and verifying new sessions
 pub struct RelayServer {
-    /// map of User IDs to corresponding user
-    users: HashMap<String, User>,
+    /// persistent store of user accounts
+    users: Box<dyn UserStore>,
     /// map of user IDs to the ID of game they're currently in
     user_games: HashMap<String, String>,
     /// map of User IDs to corresponding client session
     sessions: RelayServerSessions,
     /// map of Game IDs to corresponding game
     games: HashMap<String, Game>,
+    /// sessions watching the listed games
+    lobby: Lobby,
+    /// invite codes for games, by code
+    invites: InviteStore,
     /// random number generator
     rng: ThreadRng,
+    /// hashes and verifies user passwords
+    hasher: PasswordHasher,
+    /// failed login attempt tracking and lockout
+    login_guard: LoginGuard,
+    /// wrong game password tracking and lockout, keyed by game and user ID
+    join_guard: LoginGuard,
+    /// config of newly hosted games
+    game_config: GameConfig,
+    /// board size of newly hosted games
+    board_size: u16,
+    /// users waiting for a matched game
+    match_queue: MatchQueue,
+    matchmaking: MatchmakingConfig,
+}
+
+/// what happens to a session whose outbound queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// log the session out, the client can reconnect and `Resume`
+    Disconnect,
+    /// skip messages until the queue drains, then send one `/lagged` with the first seq skipped
+    Coalesce,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "OutboundConfig::new", deny_unknown_fields)]
+pub struct OutboundConfig {
+    /// messages queued for a session before it counts as too slow
+    pub queue_len: usize,
+    pub on_overflow: Overflow,
+}
+
+impl OutboundConfig {
+    pub fn new() -> OutboundConfig {
+        OutboundConfig {
+            queue_len: 64,
+            on_overflow: Overflow::Coalesce,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.queue_len == 0 {
+            return Err("queue_len must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+/// most sessions a user can have open at once, the oldest is logged out to make room
+const MAX_SESSIONS_PER_USER: usize = 8;
+
+/// most recent messages kept per user for `Resume`
+const MAX_OUTBOX_LEN: usize = 256;
+/// how long messages are kept for `Resume`
+const OUTBOX_TTL_MS: u64 = 10 * 60 * 1000;
+
+/// client session of a user and the token that authorized it
+struct UserSession {
+    addr: Recipient<Message>,
+    token: String,
+    /// first seq skipped while the session's queue was full
+    lagged_from: Option<u64>,
+}
+
+impl UserSession {
+    /// queue numbered message unless the session's queue is full
+    /// returns false if the session should be logged out
+    fn deliver(&mut self, message: &Message, overflow: Overflow) -> bool {
+        if let Some(seq) = self.lagged_from {
+            // the session catches up with one notice rather than every skipped message
+            match self.addr.try_send(Message::new(MsgResult::lagged(seq))) {
+                Ok(()) => self.lagged_from = None,
+                // still full, or closed and about to disconnect
+                Err(_) => return true,
+            }
+        }
+        match self.addr.try_send(message.clone()) {
+            Ok(()) | Err(SendError::Closed(_)) => true,
+            Err(SendError::Full(_)) => match overflow {
+                Overflow::Disconnect => false,
+                Overflow::Coalesce => {
+                    debug!("[srv/m] session lagging from seq {:?}", message.seq);
+                    self.lagged_from = message.seq;
+                    true
+                }
+            },
+        }
+    }
 }
 
 struct RelayServerSessions {
-    map: HashMap<String, Recipient<Message>>,
-    /// map of User IDs to corresponding session key for session verification
-    verification_keys: HashMap<String, String>,
+    /// map of User IDs to their live sessions, oldest first
+    map: HashMap<String, Vec<UserSession>>,
+    /// issued session tokens for session verification
+    tokens: TokenStore,
+    /// map of User IDs to the sequence number of the last message sent to them
+    seqs: HashMap<String, u64>,
+    /// highest seq of the users dropped from `seqs`, their seqs restart past it
+    dropped_seq: u64,
+    /// map of User IDs to their most recent messages, oldest first
+    outboxes: HashMap<String, VecDeque<Message>>,
+    outbound: OutboundConfig,
 }
 
 impl RelayServerSessions {
-    pub fn new() -> RelayServerSessions {
+    pub fn new(outbound: OutboundConfig) -> RelayServerSessions {
         RelayServerSessions {
             map: HashMap::new(),
-            verification_keys: HashMap::new(),
+            tokens: TokenStore::new(TOKEN_TTL_SECS),
+            seqs: HashMap::new(),
+            dropped_seq: 0,
+            outboxes: HashMap::new(),
+            outbound,
         }
     }
-    fn do_send_log(&self, addr: &actix::Recipient<Message>, message: String) {
-        if let Err(err) = addr.do_send(Message(message)) {
+    /// send ignoring the session's queue limit, only for the last message a session gets
+    fn do_send_log(&self, addr: &actix::Recipient<Message>, message: Message) {
+        if let Err(err) = addr.do_send(message) {
             debug!("[srv/m] do_send error: {:?}", err);
             // TODO send errors to logging record
         }
     }
-    pub fn verify_session(&mut self, msg: VerifySession) {
-        let user_id_opt = msg.user_id;
-        if let Some(user_id) = user_id_opt {
-            if let Some(sesh_key) = self.verification_keys.get(&user_id) {
-                if sesh_key == &msg.token {
-                    // user must have user_id and valid session token for session to verify
-                    if let Some(addr) = self.map.get(&user_id) {
-                        if addr == &msg.addr {
-                            return;
-                        }
-                    }
-                    self.do_send_log(&msg.addr, MsgResult::alert("new session"));
-                    // if user's session is untracked and session key is verified, replace self.sessions[user_id] with it
-                    self.map.insert(user_id.clone(), msg.addr.clone());
+
+    /// send unnumbered message unless the session's queue is full
+    fn try_send_log(&self, addr: &actix::Recipient<Message>, message: Message) {
+        if let Err(err) = addr.try_send(message) {
+            debug!("[srv/m] try_send error: {:?}", err);
+        }
+    }
+
+    /// track address as a session of user authorized by token
+    /// returns false if the address was already tracked, in which case only its token is updated
+    pub fn track_session(&mut self, user_id: &str, addr: Recipient<Message>, token: &str) -> bool {
+        let sessions = self.map.entry(user_id.to_owned()).or_default();
+        if let Some(existing) = sessions.iter_mut().find(|s| s.addr == addr) {
+            existing.token = token.to_owned();
+            return false;
+        }
+        sessions.push(UserSession {
+            addr,
+            token: token.to_owned(),
+            lagged_from: None,
+        });
+        if sessions.len() > MAX_SESSIONS_PER_USER {
+            let oldest = sessions.remove(0);
+            self.do_send_log(&oldest.addr, Message::new(MsgResult::logout("Connect")));
+        }
+        true
+    }
+
+    /// stop tracking a session of user, returns false if it wasn't tracked
+    pub fn untrack_session(&mut self, user_id: &str, addr: &Recipient<Message>) -> bool {
+        let mut found = false;
+        if let Some(sessions) = self.map.get_mut(user_id) {
+            let len = sessions.len();
+            sessions.retain(|s| &s.addr != addr);
+            found = sessions.len() != len;
+            if sessions.is_empty() {
+                self.map.remove(user_id);
+            }
+        }
+        found
+    }
+
+    pub fn verify_session(&mut self, msg: VerifySession) -> Result<String, TokenRejection> {
+        // session must present an unexpired, unrevoked token of its user to verify
+        let user_id = match self
+            .tokens
+            .verify(&msg.token, msg.user_id.as_deref(), now_unix())
+        {
+            Ok(token) => token.user_id.clone(),
+            Err(rejection) => {
+                self.do_send_log(
+                    &msg.addr,
+                    Message::new(MsgResult::logout(&format!("VerifySession {}", rejection))),
+                );
+                return Err(rejection);
+            }
+        };
+        // if session is untracked and session key is verified, add it to the user's sessions
+        if self.track_session(&user_id, msg.addr.clone(), &msg.token) {
+            self.try_send_log(&msg.addr, Message::new(MsgResult::alert("new session")));
+        }
+        Ok(user_id)
+    }
+
+    /// revoke token(s) of user and log out every session authorized by a revoked token
+    pub fn revoke_token(&mut self, user_id: &str, token: Option<&str>) {
+        match token {
+            Some(token) => {
+                if self.tokens.revoke(token, user_id).is_none() {
                     return;
                 }
             }
+            None => self.tokens.revoke_user(user_id),
+        }
+        let sessions = self.map.remove(user_id).unwrap_or_default();
+        let (revoked, kept): (Vec<UserSession>, Vec<UserSession>) = sessions
+            .into_iter()
+            .partition(|s| token.is_none_or(|t| s.token == t));
+        if !kept.is_empty() {
+            self.map.insert(user_id.to_owned(), kept);
+        }
+        for session in revoked {
+            self.do_send_log(
+                &session.addr,
+                Message::new(MsgResult::logout("RevokeToken")),
+            );
+        }
+    }
+
+    /// replace token of every session authorized by `old` with `new`
+    pub fn replace_token(&mut self, user_id: &str, old: &str, new: &str) {
+        if let Some(sessions) = self.map.get_mut(user_id) {
+            for session in sessions.iter_mut().filter(|s| s.token == old) {
+                session.token = new.to_owned();
+            }
+        }
+    }
+
+    /// number message and send it to every session of user
+    /// the message is kept in the user's outbox so sessions that missed it can `Resume`
+    pub fn send_user(&mut self, user_id: &str, msg: &str) {
+        let seq = self
+            .seqs
+            .entry(user_id.to_owned())
+            .or_insert(self.dropped_seq);
+        *seq += 1;
+        let message = Message {
+            text: msg.to_owned(),
+            seq: Some(*seq),
+            sent_unix_ms: now_unix_ms(),
+        };
+        let outbox = self.outboxes.entry(user_id.to_owned()).or_default();
+        if outbox.len() == MAX_OUTBOX_LEN {
+            outbox.pop_front();
+        }
+        outbox.push_back(message.clone());
+        let overflow = self.outbound.on_overflow;
+        let mut dropped = vec![];
+        if let Some(sessions) = self.map.get_mut(user_id) {
+            sessions.retain_mut(|session| {
+                let keep = session.deliver(&message, overflow);
+                if !keep {
+                    dropped.push(session.addr.clone());
+                }
+                keep
+            });
+        }
+        // unconditional so the logout isn't lost to the full queue
+        for addr in dropped {
+            debug!("[srv/m] {} session too slow, logging out", user_id);
+            self.do_send_log(&addr, Message::new(MsgResult::logout("lagging")));
+        }
+    }
+
+    /// resend messages after `last_seq` to a session of user, returns the number resent
+    /// fails if some of those messages are no longer kept
+    /// the session's queue limits the replay the same as new messages
+    pub fn replay(
+        &mut self,
+        user_id: &str,
+        addr: &Recipient<Message>,
+        last_seq: u64,
+    ) -> Result<usize, RelayError> {
+        let missed: Vec<Message> = self
+            .missed(user_id, last_seq)?
+            .into_iter()
+            .cloned()
+            .collect();
+        let overflow = self.outbound.on_overflow;
+        let session = self
+            .map
+            .get_mut(user_id)
+            .and_then(|sessions| sessions.iter_mut().find(|s| &s.addr == addr));
+        let keep = match session {
+            Some(session) => missed.iter().all(|m| session.deliver(m, overflow)),
+            // only the user's own sessions are replayed to
+            None => return Ok(0),
+        };
+        if !keep {
+            debug!("[srv/m] {} session too slow, logging out", user_id);
+            self.untrack_session(user_id, addr);
+            self.do_send_log(addr, Message::new(MsgResult::logout("lagging")));
+        }
+        Ok(missed.len())
+    }
+
+    /// messages of user after `last_seq`, oldest first
+    fn missed(&self, user_id: &str, last_seq: u64) -> Result<Vec<&Message>, RelayError> {
+        let latest = self.seqs.get(user_id).copied().unwrap_or(0);
+        let outbox = self.outboxes.get(user_id);
+        let oldest_seq = outbox
+            .and_then(|o| o.front())
+            .and_then(|m| m.seq)
+            .unwrap_or(latest + 1);
+        // a gap before the oldest kept message, or a seq never sent, can't be replayed
+        if last_seq > latest || last_seq + 1 < oldest_seq {
+            return Err(RelayError::ReplayUnavailable {
+                last_seq,
+                oldest_seq,
+            });
         }
-        self.do_send_log(&msg.addr, MsgResult::logout("VerifySession"));
+        Ok(outbox
+            .into_iter()
+            .flatten()
+            .filter(|m| m.seq > Some(last_seq))
+            .collect())
     }
-    pub fn send_user(&self, user_id: &str, msg: &str) {
-        if let Some(session) = self.map.get(user_id) {
-            self.do_send_log(session, msg.into());
+
+    /// forget messages kept longer than the outbox TTL
+    /// and the seqs of users left with no messages and no sessions
+    pub fn purge_outboxes(&mut self, now_ms: u64) {
+        for outbox in self.outboxes.values_mut() {
+            while matches!(outbox.front(), Some(m) if m.sent_unix_ms + OUTBOX_TTL_MS < now_ms) {
+                outbox.pop_front();
+            }
         }
-        // TODO log missing sessions
+        self.outboxes.retain(|_, outbox| !outbox.is_empty());
+        let outboxes = &self.outboxes;
+        let map = &self.map;
+        let dropped_seq = &mut self.dropped_seq;
+        self.seqs.retain(|user_id, seq| {
+            let keep = outboxes.contains_key(user_id)
+                || map
+                    .get(user_id)
+                    .is_some_and(|sessions| !sessions.is_empty());
+            if !keep {
+                *dropped_seq = (*dropped_seq).max(*seq);
+            }
+            keep
+        });
     }
+
     pub fn send_all(
-        &self,
+        &mut self,
         keys: std::collections::hash_map::Keys<'_, std::string::String, Player>,
         msg: &str,
     ) {
@@ -196,7 +768,20 @@ impl RelayServerSessions {
         }
     }
 
-    pub fn send_player_game_data(&self, user_id: String, game: &Game) {
+    /// send changes to the public game since the last update to every player but `except`
+    pub fn send_game_update(&mut self, game: &mut Game, except: Option<&str>) {
+        if let Some(update) = game.take_update() {
+            let msg = MsgResult::game_update(&update)
+                .unwrap_or_else(|e| MsgResult::error("game_update", &e));
+            for user_id in game.players.keys() {
+                if Some(user_id.as_str()) != except {
+                    self.send_user(user_id, &msg);
+                }
+            }
+        }
+    }
+
+    pub fn send_player_game_data(&mut self, user_id: String, game: &Game) {
         // send action points update to host
         let game_id = game.game_id.clone();
         let apu = ActionPointUpdate::new(&user_id, &game_id, game.players[&user_id].action_points);
@@ -215,98 +800,397 @@ impl RelayServerSessions {
     }
 }
 
+/// how often expired session tokens, stale login failures and old outbox messages are forgotten
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Make actor from `RelaySever`
 impl Actor for RelayServer {
     // Simple context
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(PURGE_INTERVAL, |act, _| {
+            let now = now_unix();
+            act.sessions.tokens.purge_expired(now);
+            act.sessions.purge_outboxes(now_unix_ms());
+            act.login_guard.purge(now);
+            act.join_guard.purge(now);
+            act.invites.purge_expired(now);
+        });
+    }
 }
 
 impl RelayServer {
-    pub fn new() -> RelayServer {
+    pub fn new(
+        users: Box<dyn UserStore>,
+        hasher: PasswordHasher,
+        login_guard: LoginGuard,
+        game_config: GameConfig,
+        board_size: u16,
+        outbound: OutboundConfig,
+        matchmaking: MatchmakingConfig,
+    ) -> RelayServer {
         RelayServer {
-            users: HashMap::new(),
+            users,
             user_games: HashMap::new(),
-            sessions: RelayServerSessions::new(),
+            sessions: RelayServerSessions::new(outbound),
             games: HashMap::new(),
+            lobby: Lobby::default(),
+            invites: InviteStore::default(),
             rng: rand::thread_rng(),
+            hasher,
+            // game passwords get the same limits as user passwords
+            join_guard: LoginGuard::new(login_guard.config().clone()),
+            login_guard,
+            game_config,
+            board_size,
+            match_queue: MatchQueue::default(),
+            matchmaking,
+        }
+    }
+
+    /// look up user logging in unless user or IP is locked out by too many failures
+    /// unknown users count towards lockout
+    fn begin_login(&mut self, user_id: &str, ip: Option<&str>) -> Result<User, Fail> {
+        let now = now_unix();
+        self.login_guard
+            .check(user_id, ip, now)
+            .map_err(|retry_after_secs| Fail::Locked { retry_after_secs })?;
+        let existant = self.users.get(user_id).map_err(store_error)?;
+        existant.ok_or_else(|| {
+            self.login_guard.record_failure(user_id, ip, now);
+            Fail::UnknownUser
+        })
+    }
+
+    /// finish login with the verification of user's password, storing a rehashed password
+    /// failed verification counts towards lockout, returns user ID
+    fn finish_login(
+        &mut self,
+        mut existant: User,
+        verified: Result<Verified, String>,
+        ip: Option<&str>,
+    ) -> Result<String, Fail> {
+        let verified = match verified {
+            Ok(verified) => verified,
+            Err(_) => {
+                self.login_guard
+                    .record_failure(&existant.user_id, ip, now_unix());
+                return Err(Fail::Password);
+            }
+        };
+        if let Verified::Rehashed(password_hash) = verified {
+            existant.password_hash = password_hash;
+            self.users.insert(existant.clone()).map_err(store_error)?;
+        }
+        self.login_guard.record_success(&existant.user_id);
+        Ok(existant.user_id)
+    }
+
+    /// validate credentials of a new user, returns normalized user ID
+    fn validate_registration(&self, user_id: &str, password: &str) -> Result<String, Fail> {
+        let user_id = validate_user_id(user_id).map_err(Fail::InvalidUserId)?;
+        check_password_strength(&user_id, password).map_err(Fail::WeakPassword)?;
+        if self.users.get(&user_id).map_err(store_error)?.is_some() {
+            return Err(Fail::UserExists);
+        }
+        Ok(user_id)
+    }
+
+    /// store new user unless the ID was taken while their password was hashed, returns user ID
+    fn create_user(&mut self, user: User) -> Result<String, Fail> {
+        if self
+            .users
+            .get(&user.user_id)
+            .map_err(store_error)?
+            .is_some()
+        {
+            return Err(Fail::UserExists);
         }
+        let user_id = user.user_id.clone();
+        self.users.insert(user).map_err(store_error)?;
+        Ok(user_id)
+    }
+
+    /// reply to `Connect` or `Register`, opening a session for the user on success
+    fn connect_result(
+        &mut self,
+        res: Result<String, Fail>,
+        addr: Option<Recipient<Message>>,
+        alert: &str,
+    ) -> ConnectResult {
+        let res = match res {
+            Err(fail) => ConnectResult::Fail(fail),
+            Ok(user_id) => {
+                // The HTTP POST:login endpoint uses Connect {} to log in the user
+                // There is no socket in that case so addr has to be None
+                let token = self.open_session(&user_id, addr);
+                ConnectResult::Success(SuccessResult {
+                    user_id,
+                    alert: alert.into(),
+                    token: Some(token.token),
+                    token_expires_unix: Some(token.expires_unix),
+                })
+            }
+        };
+        debug!("{:?}", &res);
+        res
+    }
+
+    /// issue a session token for logged in user
+    /// if address included, track it as one of the user's sessions
+    fn open_session(&mut self, user_id: &str, addr: Option<Recipient<Message>>) -> SessionToken {
+        let token = self.sessions.tokens.issue(user_id, now_unix());
+        if let Some(addr) = addr {
+            self.sessions.track_session(user_id, addr, &token.token);
+        }
+        token
+    }
+
+    /// token of the credential if it is valid, session tokens must belong to the session's user
+    fn verify_credential(&self, credential: &Credential) -> Result<&SessionToken, TokenRejection> {
+        let (token, user_id) = match credential {
+            Credential::SessionUser { user_id, token } => (token, Some(user_id.as_str())),
+            Credential::BearerToken(token) => (token, None),
+        };
+        self.sessions.tokens.verify(token, user_id, now_unix())
+    }
+
+    /// config and board size of a game matched with `prefs`
+    /// matched games are full from the start and can only be found by their players
+    fn match_game_config(&self, prefs: &MatchPrefs) -> Result<(GameConfig, u16), RelayError> {
+        let mut config = self.game_config.clone();
+        config.visibility = Visibility::Unlisted;
+        config.max_players = self.matchmaking.players as u16;
+        if let Some(turn_time_secs) = prefs.turn_time_secs {
+            config.turn_time_secs = turn_time_secs;
+        }
+        let board_size = prefs.board_size.unwrap_or(self.board_size);
+        config.validate(board_size)?;
+        Ok((config, board_size))
+    }
+
+    /// start games for every group of compatible users in the queue
+    /// users of a game that fails to start are told and queued again
+    fn make_matches(&mut self, ctx: &mut Context<Self>) {
+        let mut failed = vec![];
+        while let Some((tickets, prefs)) = self.match_queue.take_match(self.matchmaking.players) {
+            let user_ids: Vec<String> = tickets.iter().map(|t| t.user_id.clone()).collect();
+            if let Err(e) = self.start_match(&user_ids, &prefs, ctx) {
+                debug!("[srv/m] match failed: {:?}", e);
+                let msg = MsgResult::error("find_match", &format!("{}, still waiting", e));
+                for user_id in &user_ids {
+                    self.sessions.send_user(user_id, &msg);
+                }
+                failed.extend(tickets);
+            }
+        }
+        // queued after matching so the same group isn't taken again straight away
+        self.match_queue.requeue(failed);
+    }
+
+    /// create game with matched users as players and start it, then send it to them
+    /// users are only put in the game once it has started
+    fn start_match(
+        &mut self,
+        user_ids: &[String],
+        prefs: &MatchPrefs,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), RelayError> {
+        let (config, board_size) = self.match_game_config(prefs)?;
+        let game_id = loop {
+            let game_id = format!("match_{}", &gen_token()[..12]);
+            if !self.games.contains_key(&game_id) {
+                break game_id;
+            }
+        };
+        let mut game = Game::new(game_id.clone(), board_size, config, self.rng);
+        for user_id in user_ids {
+            game.insert_player(user_id.clone())?;
+        }
+        game.start_game()?;
+        // players get the full game below
+        game.take_update();
+        let msg = MsgResult::match_found(&game).map_err(RelayError::Internal)?;
+        let game = self.games.entry(game_id.clone()).or_insert(game);
+        for user_id in user_ids {
+            self.user_games.insert(user_id.clone(), game_id.clone());
+            self.sessions.send_user(user_id, &msg);
+            self.sessions.send_player_game_data(user_id.clone(), game);
+        }
+        announce_start(&mut self.sessions, game, ctx)
+    }
+
+    /// game the user is playing or waiting in, if it still has them as a player
+    fn current_game(&self, user_id: &str) -> Option<String> {
+        self.user_games
+            .get(user_id)
+            .and_then(|game_id| self.games.get(game_id))
+            .filter(|game| game.players.contains_key(user_id))
+            .map(|game| game.game_id.clone())
     }
 }
 
-/// Checks if user exists, if so success if passwords match else fails
-/// replaces current session address
-/// Creates new user if none exists, setting password and session address
-/// If Address included, creates a new session key that handles updating sessions
+/// `join_guard` key of user's attempts at a game's password
+/// user IDs have no spaces so keys of different games and users can't collide
+fn join_guard_key(game_id: &str, user_id: &str) -> String {
+    format!("{} {}", game_id, user_id)
+}
+
+fn store_error(e: String) -> Fail {
+    debug!("[srv/m] user store error: {:?}", e);
+    Fail::Internal
+}
+
+/// Checks if user exists, if so success if password verifies against stored hash else fails
+/// rehashes stored password if hash parameters have changed
+/// Fails with `Fail::UnknownUser` if no user exists, accounts are created with `Register`
+/// Fails with `Fail::Locked` while user or IP is locked out after repeated failures
+/// Issues a session token, if Address included, adds it to the user's sessions
 impl Handler<Connect> for RelayServer {
-    type Result = MessageResult<Connect>;
-    #[allow(unused_variables)]
+    type Result = ResponseActFuture<Self, ConnectResult>;
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
         debug!("{:?}", &msg);
-        let User { user_id, password } = msg.user.clone();
-        let mut res = match self.users.get(&user_id) {
-            Some(existant) => {
-                if existant.password == password {
-                    ConnectResult::Success(SuccessResult {
-                        alert: "user exists".into(),
-                        token: None,
+        let Connect {
+            user_id,
+            password,
+            addr,
+            ip,
+        } = msg;
+        let user_id = normalize_user_id(&user_id);
+        let existant = match self.begin_login(&user_id, ip.as_deref()) {
+            Ok(existant) => existant,
+            Err(fail) => return Box::pin(fut::ready(self.connect_result(Err(fail), addr, ""))),
+        };
+        // verified off the actor so other sessions aren't held up by hashing
+        let verify = self
+            .hasher
+            .verify_in_pool(password, existant.password_hash.clone());
+        Box::pin(verify.into_actor(self).map(move |verified, act, _| {
+            let res = act.finish_login(existant, verified, ip.as_deref());
+            act.connect_result(res, addr, "user exists")
+        }))
+    }
+}
+
+/// Validates user ID and password strength then creates user
+/// If Address included, session is tracked the same as `Connect`
+impl Handler<Register> for RelayServer {
+    type Result = ResponseActFuture<Self, ConnectResult>;
+    fn handle(&mut self, msg: Register, _: &mut Context<Self>) -> Self::Result {
+        debug!("{:?}", &msg);
+        let Register {
+            user_id,
+            password,
+            addr,
+        } = msg;
+        let user_id = match self.validate_registration(&user_id, &password) {
+            Ok(user_id) => user_id,
+            Err(fail) => return Box::pin(fut::ready(self.connect_result(Err(fail), addr, ""))),
+        };
+        // hashed off the actor so other sessions aren't held up by hashing
+        let hash = self.hasher.hash_in_pool(password);
+        Box::pin(hash.into_actor(self).map(move |password_hash, act, _| {
+            let res = password_hash
+                .map_err(store_error)
+                .and_then(|password_hash| {
+                    act.create_user(User {
+                        user_id,
+                        password_hash,
                     })
-                } else {
-                    ConnectResult::Fail(Fail::Password)
-                }
-            }
-            None => {
-                self.users.insert(user_id.clone(), msg.user);
+                });
+            act.connect_result(res, addr, "user created")
+        }))
+    }
+}
+
+impl Handler<CheckCredential> for RelayServer {
+    type Result = MessageResult<CheckCredential>;
+    fn handle(&mut self, msg: CheckCredential, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.verify_credential(&msg.0).map(|t| t.user_id.clone()))
+    }
+}
+
+/// credentials must hold a valid token, which is kept as the session's token
+/// so revoking or expiring it cuts off sessions attached with it
+impl Handler<Attach> for RelayServer {
+    type Result = MessageResult<Attach>;
+    fn handle(&mut self, msg: Attach, _: &mut Context<Self>) -> Self::Result {
+        debug!("{:?}", &msg);
+        let res = match self.verify_credential(&msg.credential) {
+            Ok(verified) => {
+                let verified = verified.clone();
+                self.sessions
+                    .track_session(&verified.user_id, msg.addr, &verified.token);
                 ConnectResult::Success(SuccessResult {
-                    alert: "user created".into(),
-                    token: None,
+                    user_id: verified.user_id,
+                    alert: "session attached".into(),
+                    token: Some(verified.token),
+                    token_expires_unix: Some(verified.expires_unix),
                 })
             }
+            Err(rejection) => ConnectResult::Fail(Fail::Token(rejection)),
         };
-
-        // The HTTP GET:login endpoint uses Connect {} to log in the user
-        // There is no socket in that case so msg.addr has to be None
-        debug!("{:?}", &msg.addr);
-        if let ConnectResult::Success(ref mut succ_res) = res {
-            if msg.addr.is_some() {
-                let addr = msg.addr.expect("no address in msg");
-                let old_sesh = self.sessions.map.insert(user_id.clone(), addr.clone());
-                if let Some(res_addr) = old_sesh {
-                    if res_addr != addr {
-                        self.sessions
-                            .do_send_log(&res_addr, MsgResult::logout("Connect"));
-                    }
-                };
-                // new session key used for determining newest authorized session of user
-                let key = gen_rng_string(4);
-                succ_res.token = Some(key.clone());
-                self.sessions.verification_keys.insert(user_id.clone(), key);
-            }
-        }
         debug!("{:?}", &res);
-        return MessageResult(res);
+        MessageResult(res)
     }
 }
 
-/// session key will determine if a conflicting session verifying will logout
+/// session token will determine if a conflicting session verifying will logout
 /// or replace an existing session
-/// TODO recover messages missed transitioning to new session
-/// TODO add timestamp to each message for clients to differentiate resent messages
+/// expired, revoked or unknown tokens are logged out with the rejection reason
+/// messages missed transitioning to the new session are recovered with `Resume`
 impl Handler<VerifySession> for RelayServer {
+    type Result = MessageResult<VerifySession>;
+    fn handle(&mut self, msg: VerifySession, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.sessions.verify_session(msg))
+    }
+}
+
+impl Handler<RefreshToken> for RelayServer {
+    type Result = MessageResult<RefreshToken>;
+    fn handle(&mut self, msg: RefreshToken, _: &mut Context<Self>) -> Self::Result {
+        let res = self
+            .sessions
+            .tokens
+            .refresh(&msg.token, &msg.user_id, now_unix());
+        if let Ok(token) = &res {
+            // keep sessions authorized by the token that replaced their old one
+            self.sessions
+                .replace_token(&msg.user_id, &msg.token, &token.token);
+        }
+        MessageResult(res)
+    }
+}
+
+impl Handler<RevokeToken> for RelayServer {
     type Result = ();
-    fn handle(&mut self, msg: VerifySession, _: &mut Context<Self>) {
-        self.sessions.verify_session(msg);
+    fn handle(&mut self, msg: RevokeToken, _: &mut Context<Self>) {
+        self.sessions
+            .revoke_token(&msg.user_id, msg.token.as_deref());
+    }
+}
+
+impl Handler<Resume> for RelayServer {
+    type Result = MessageResult<Resume>;
+    fn handle(&mut self, msg: Resume, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.sessions.replay(&msg.user_id, &msg.addr, msg.last_seq))
     }
 }
 
 impl Handler<Disconnect> for RelayServer {
     type Result = ();
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        let res = self.sessions.map.remove(&msg.user_id);
-        if res.is_some() {
+        self.lobby.unwatch(&msg.addr);
+        if self.sessions.untrack_session(&msg.user_id, &msg.addr) {
             debug!("disconnected {:?}", msg);
         } else {
             debug!("unknown {:?}", msg);
         }
+        // users that went away aren't matched into games they can't play
+        if !self.sessions.map.contains_key(&msg.user_id) {
+            self.match_queue.cancel(&msg.user_id);
+        }
     }
 }
 
@@ -327,14 +1211,16 @@ impl Handler<HostGame> for RelayServer {
             if game.host_user_id == Some(host_user_id.clone()) {
                 res_game = Some(game.clone());
             } else {
-                return MessageResult(Err(format!("{} exists", game_id).to_owned()));
+                return MessageResult(Err(RelayError::GameExists { game_id }));
             }
         }
         // ELSE return err if user is already in another game
         else if let Some(game_id) = self.user_games.get(&host_user_id) {
             if let Some(game) = self.games.get(game_id) {
                 if game.host_user_id == Some(host_user_id.clone()) {
-                    return MessageResult(Err("already in another game".into()));
+                    return MessageResult(Err(RelayError::AlreadyInGame {
+                        game_id: game_id.clone(),
+                    }));
                 }
             }
             debug!(
@@ -347,14 +1233,22 @@ impl Handler<HostGame> for RelayServer {
         let mut new_game = false;
         // create game and set user as host and track in user_games, return err if host op failed
         if res_game.is_none() {
-            let mut game = Game::new(game_id.clone(), BOARD_SIZE, self.rng.clone());
-            let host_op = game.set_host(host_user_id.clone()).map(|_| ());
-            if host_op.is_err() {
-                return MessageResult(host_op);
+            let mut game = Game::new(
+                game_id.clone(),
+                self.board_size,
+                self.game_config.clone(),
+                self.rng,
+            );
+            if let Err(e) = game.set_host(host_user_id.clone()) {
+                return MessageResult(Err(e.into()));
             }
+            // host gets the full game below
+            game.take_update();
+            self.lobby.publish(&game);
             self.games.insert(game_id.clone(), game.clone());
             self.user_games
                 .insert(host_user_id.clone(), game_id.clone());
+            self.match_queue.cancel(&host_user_id);
             res_game = Some(game);
             new_game = true;
         }
@@ -374,31 +1268,104 @@ impl Handler<HostGame> for RelayServer {
                 };
                 self.sessions.send_user(&host_user_id, &alert);
             })
-            .map_err(|e| format!("{:?}", e).to_owned());
+            .map_err(RelayError::Internal);
         MessageResult(res)
     }
 }
 
 impl Handler<JoinGame> for RelayServer {
-    type Result = MessageResult<JoinGame>;
+    type Result = ResponseActFuture<Self, Result<(), RelayError>>;
     fn handle(&mut self, msg: JoinGame, _: &mut Context<Self>) -> Self::Result {
-        let JoinGame { game_id, user_id } = msg;
+        let JoinGame {
+            game_id,
+            user_id,
+            password,
+            ip,
+        } = msg;
+        let password_hash = match self.password_to_verify(&game_id, &user_id) {
+            Some(password_hash) => password_hash,
+            None => return Box::pin(fut::ready(self.join_game(game_id, user_id, None))),
+        };
+        // wrong passwords lock out the guesser and their IP, not the game
+        let guard_key = join_guard_key(&game_id, &user_id);
+        let now = now_unix();
+        if let Err(retry_after_secs) = self.join_guard.check(&guard_key, ip.as_deref(), now) {
+            return Box::pin(fut::ready(Err(RelayError::JoinLocked {
+                game_id,
+                retry_after_secs,
+            })));
+        }
+        // verified off the actor so other sessions aren't held up by hashing
+        let verify = self
+            .hasher
+            .verify_in_pool(password.unwrap_or_default(), password_hash.clone());
+        Box::pin(verify.into_actor(self).map(move |verified, act, _| {
+            if verified.is_err() {
+                act.join_guard
+                    .record_failure(&guard_key, ip.as_deref(), now);
+                return Err(GameError::WrongPassword.into());
+            }
+            act.join_guard.record_success(&guard_key);
+            act.join_game(game_id, user_id, Some(&password_hash))
+        }))
+    }
+}
+
+impl RelayServer {
+    /// hash to verify user's password against before they join a game by ID
+    /// `None` if the join doesn't take a password
+    fn password_to_verify(&self, game_id: &str, user_id: &str) -> Option<String> {
+        if self.invites.get(game_id).is_some() {
+            return None;
+        }
+        self.games
+            .get(game_id)
+            .filter(|game| game.requires_password(user_id))
+            .map(|game| game.password_hash().unwrap_or_default().to_owned())
+    }
+
+    /// put user in the game with ID or invite code `game_id` and tell everyone concerned
+    /// `verified_hash` is the game password hash the user's password was verified against
+    fn join_game(
+        &mut self,
+        game_id: String,
+        user_id: String,
+        verified_hash: Option<&str>,
+    ) -> Result<(), RelayError> {
+        // look the ID up as an invite code before as a game ID
+        let invite = match self.invites.get(&game_id) {
+            Some(invite) if invite.is_expired(now_unix()) => {
+                return Err(RelayError::InviteExpired { code: game_id })
+            }
+            Some(invite) => Some(invite.clone()),
+            None => None,
+        };
+        let game_id = invite.as_ref().map_or(game_id, |i| i.game_id.clone());
         // return err if user already in a game
         if let Some(cur_game_id) = self.user_games.get(&user_id) {
             if cur_game_id != &game_id {
-                return MessageResult(Err("already in a another game".into()));
+                return Err(RelayError::AlreadyInGame {
+                    game_id: cur_game_id.clone(),
+                });
             }
         }
         let mut insert_player_result = InsertPlayerResult::Joined;
         let user_games = &mut self.user_games;
-        let sessions = &self.sessions;
+        let sessions = &mut self.sessions;
+        let lobby = &mut self.lobby;
         // get game
         let res = self
             .games
             .get_mut(&game_id)
-            .ok_or("game not found".to_owned())
+            .ok_or_else(|| RelayError::GameNotFound {
+                game_id: game_id.clone(),
+            })
             // insert player into game (may error) and track user_id to game_id
             .and_then(|game| {
+                // an invite lets the user in whatever the game's visibility
+                if invite.is_none() {
+                    game.check_access(&user_id, verified_hash)?;
+                }
                 insert_player_result = game.insert_player(user_id.clone())?;
                 // dont lock user into game if game is over
                 if !game.is_end_phase() {
@@ -417,6 +1384,8 @@ impl Handler<JoinGame> for RelayServer {
                             sessions.send_user(k, &msg);
                         }
                     }
+                    sessions.send_game_update(game, Some(&user_id));
+                    lobby.publish(game);
                 }
                 let msg = MsgResult::join_game(&game)
                     .unwrap_or_else(|e| MsgResult::error("join_game", &e));
@@ -426,38 +1395,120 @@ impl Handler<JoinGame> for RelayServer {
                 sessions.send_player_game_data(user_id.clone(), &game);
                 Ok(())
             });
+        if res.is_ok() {
+            self.match_queue.cancel(&user_id);
+        }
+        // tell the host who their invite brought in
+        if let (Ok(()), InsertPlayerResult::Joined, Some(invite)) =
+            (&res, insert_player_result, invite)
+        {
+            let host_user_id = self
+                .games
+                .get(&game_id)
+                .and_then(|g| g.host_user_id.clone());
+            if let (Some(invite), Some(host_user_id)) =
+                (self.invites.redeem(&invite.code), host_user_id)
+            {
+                let msg = MsgResult::invite_redeemed(&invite, &user_id)
+                    .unwrap_or_else(|e| MsgResult::error("invite_redeemed", &e));
+                self.sessions.send_user(&host_user_id, &msg);
+            }
+        }
+        res
+    }
+}
+
+impl Handler<CreateInvite> for RelayServer {
+    type Result = MessageResult<CreateInvite>;
+    fn handle(&mut self, msg: CreateInvite, _: &mut Context<Self>) -> Self::Result {
+        let CreateInvite {
+            user_id,
+            game_id,
+            ttl_secs,
+            single_use,
+        } = msg;
+        let res = match self.games.get(&game_id) {
+            None => Err(RelayError::GameNotFound { game_id }),
+            Some(game) if game.host_user_id.as_ref() != Some(&user_id) => {
+                Err(RelayError::NotHost { game_id })
+            }
+            Some(_) => Ok(self
+                .invites
+                .mint(&game_id, ttl_secs, single_use, now_unix())),
+        };
         MessageResult(res)
     }
 }
 
 impl Handler<ConfigGame> for RelayServer {
-    type Result = ();
+    type Result = ResponseActFuture<Self, Result<(), RelayError>>;
     fn handle(&mut self, msg: ConfigGame, _: &mut Context<Self>) -> Self::Result {
         let ConfigGame {
             game_id,
             user_id,
             op,
         } = msg;
-        let sessions = &self.sessions;
+        // games only keep the hash of their password
+        let password = match op {
+            ConfigGameOp::Password(password) if !password.is_empty() => password,
+            op => return Box::pin(fut::ready(self.config_game(&game_id, &user_id, op))),
+        };
+        // only the host's passwords are worth hashing
+        let is_host = match self.games.get(&game_id) {
+            None => Err(RelayError::GameNotFound {
+                game_id: game_id.clone(),
+            }),
+            Some(game) if game.host_user_id.as_ref() != Some(&user_id) => {
+                Err(RelayError::NotHost {
+                    game_id: game_id.clone(),
+                })
+            }
+            Some(_) => Ok(()),
+        };
+        if let Err(e) = is_host {
+            return Box::pin(fut::ready(Err(e)));
+        }
+        // hashed off the actor so other sessions aren't held up by hashing
+        let hash = self.hasher.hash_in_pool(password);
+        Box::pin(hash.into_actor(self).map(move |password_hash, act, _| {
+            let op = ConfigGameOp::Password(password_hash.map_err(RelayError::Internal)?);
+            act.config_game(&game_id, &user_id, op)
+        }))
+    }
+}
+
+impl RelayServer {
+    /// apply host's change to game, sending it to the game's players
+    fn config_game(
+        &mut self,
+        game_id: &str,
+        user_id: &str,
+        op: ConfigGameOp,
+    ) -> Result<(), RelayError> {
+        let sessions = &mut self.sessions;
+        let lobby = &mut self.lobby;
         self.games
-            .get_mut(&game_id)
-            .ok_or("Game not found".to_owned())
+            .get_mut(game_id)
+            .ok_or_else(|| RelayError::GameNotFound {
+                game_id: game_id.to_owned(),
+            })
             .and_then(|game| {
-                if game.host_user_id != Some(user_id.clone()) {
-                    return Err("only host can configure game".to_owned());
+                if game.host_user_id.as_deref() != Some(user_id) {
+                    return Err(RelayError::NotHost {
+                        game_id: game_id.to_owned(),
+                    });
                 }
-                game.configure(&op)
-                    .map(|res| (MsgResult::conf_game(&game, &res), game))
+                let res = game.configure(&op)?;
+                lobby.publish(game);
+                Ok((MsgResult::conf_game(game, &res), game))
             })
             .and_then(|(msg_result, game)| {
-                let json = msg_result?;
-                // send game
+                let json = msg_result.map_err(RelayError::Internal)?;
+                // send changes then what moved
+                sessions.send_game_update(game, None);
                 sessions.send_all(game.players.keys(), &json);
                 Ok(())
             })
-            .unwrap_or_else(|e| {
-                sessions.send_user(&user_id, &MsgResult::error("conf_game", &e));
-            });
     }
 }
 
@@ -465,75 +1516,181 @@ impl Handler<StartGame> for RelayServer {
     type Result = MessageResult<StartGame>;
     fn handle(&mut self, msg: StartGame, ctx: &mut Context<Self>) -> Self::Result {
         let StartGame { game_id, user_id } = msg;
-        let sessions = &self.sessions;
+        let sessions = &mut self.sessions;
+        let lobby = &mut self.lobby;
         let res = self
             .games
             .get_mut(&game_id)
-            .ok_or("Game not found".to_owned())
+            .ok_or_else(|| RelayError::GameNotFound {
+                game_id: game_id.clone(),
+            })
             .and_then(|game| {
                 if game.host_user_id != Some(user_id.clone()) {
-                    return Err("Only host can start game".to_owned());
+                    return Err(RelayError::NotHost {
+                        game_id: game_id.clone(),
+                    });
                 }
-                game.start_game()
-                    .map(|_| (MsgResult::start_game(&game), game))
+                game.start_game()?;
+                lobby.publish(game);
+                Ok(game)
             })
-            .and_then(|(msg_result, game)| {
-                let json = msg_result?;
-                // send game
-                sessions.send_all(game.players.keys(), &json);
-                // send action points to each player
-                for (player_id, player) in &game.players {
-                    let apu = ActionPointUpdate::new(player_id, &game_id, player.action_points);
-                    let msg: String = MsgResult::action_point_update(&apu)
-                        .unwrap_or_else(|e| MsgResult::error("action_point_update", &e));
-                    sessions.send_user(&player_id, &msg);
-                }
-                // schedule replenish
-                ctx.notify_later(
-                    Replenish {
-                        game_id: game.game_id.clone(),
-                    },
-                    Duration::from_secs(game.config.turn_time_secs),
-                );
-                // schedule action point spawn
-                ctx.notify_later(
-                    SpawnTileHeart {
-                        game_id: game.game_id.clone(),
-                    },
-                    Duration::from_millis(game.new_item_spawn_time_ms()),
-                );
-                Ok(())
-            });
+            .and_then(|game| announce_start(sessions, game, ctx));
         MessageResult(res)
     }
 }
 
+/// send players the started game, their action points and schedule the first turn
+fn announce_start(
+    sessions: &mut RelayServerSessions,
+    game: &mut Game,
+    ctx: &mut Context<RelayServer>,
+) -> Result<(), RelayError> {
+    let json = MsgResult::start_game(game).map_err(RelayError::Internal)?;
+    // send changes then announce the start
+    sessions.send_game_update(game, None);
+    sessions.send_all(game.players.keys(), &json);
+    // send action points to each player
+    for (player_id, player) in &game.players {
+        let apu = ActionPointUpdate::new(player_id, &game.game_id, player.action_points);
+        let msg: String = MsgResult::action_point_update(&apu)
+            .unwrap_or_else(|e| MsgResult::error("action_point_update", &e));
+        sessions.send_user(player_id, &msg);
+    }
+    // schedule replenish
+    ctx.notify_later(
+        Replenish {
+            game_id: game.game_id.clone(),
+        },
+        Duration::from_secs(game.config.turn_time_secs),
+    );
+    // schedule action point spawn
+    ctx.notify_later(
+        SpawnTileHeart {
+            game_id: game.game_id.clone(),
+        },
+        Duration::from_millis(game.new_item_spawn_time_ms()),
+    );
+    Ok(())
+}
+
+impl Handler<FindMatch> for RelayServer {
+    type Result = MessageResult<FindMatch>;
+    fn handle(&mut self, msg: FindMatch, ctx: &mut Context<Self>) -> Self::Result {
+        let FindMatch { user_id, prefs } = msg;
+        if let Some(game_id) = self.current_game(&user_id) {
+            return MessageResult(Err(RelayError::AlreadyInGame { game_id }));
+        }
+        if let Err(e) = self.match_game_config(&prefs) {
+            return MessageResult(Err(e));
+        }
+        self.match_queue.enqueue(&user_id, prefs);
+        self.make_matches(ctx);
+        let status = match self.match_queue.contains(&user_id) {
+            true => MatchStatus {
+                game_id: None,
+                waiting: self.match_queue.compatible(&user_id),
+            },
+            false => MatchStatus {
+                game_id: self.current_game(&user_id),
+                waiting: 0,
+            },
+        };
+        MessageResult(Ok(status))
+    }
+}
+
+impl Handler<CancelMatch> for RelayServer {
+    type Result = bool;
+    fn handle(&mut self, msg: CancelMatch, _: &mut Context<Self>) -> bool {
+        self.match_queue.cancel(&msg.user_id)
+    }
+}
+
 impl Handler<UserStatus> for RelayServer {
-    type Result = ();
-    fn handle(&mut self, msg: UserStatus, _: &mut Context<Self>) {
+    type Result = MessageResult<UserStatus>;
+    fn handle(&mut self, msg: UserStatus, _: &mut Context<Self>) -> Self::Result {
         let user_id = msg.user_id;
-        let games = &self.games;
-        let res = self
-            .user_games
-            .get(&user_id)
-            .and_then(|game_id| games.get(game_id))
-            .and_then(|game| match game.players.contains_key(&user_id) {
-                true => Some(UserStatusResult {
-                    game_id: Some(game.game_id.clone()),
-                }),
-                false => None,
-            })
-            .unwrap_or(UserStatusResult { game_id: None });
+        let res = UserStatusResult {
+            game_id: self.current_game(&user_id),
+        };
         let msg = match MsgResult::user_status(&res) {
             Ok(msg) => msg,
             Err(e) => MsgResult::error("user_status", &e),
         };
         self.sessions.send_user(&user_id, &msg);
+        MessageResult(res)
+    }
+}
+
+impl Handler<ListGames> for RelayServer {
+    type Result = MessageResult<ListGames>;
+    fn handle(&mut self, msg: ListGames, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(lobby::list(self.games.values(), &msg.filter))
+    }
+}
+
+impl Handler<WatchLobby> for RelayServer {
+    type Result = MessageResult<WatchLobby>;
+    fn handle(&mut self, msg: WatchLobby, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.lobby.watch(msg.addr, msg.filter, self.games.values()))
+    }
+}
+
+impl Handler<PublicGame> for RelayServer {
+    type Result = MessageResult<PublicGame>;
+    fn handle(&mut self, msg: PublicGame, _: &mut Context<Self>) -> Self::Result {
+        let is_player = |game: &Game| {
+            msg.user_id
+                .as_ref()
+                .is_some_and(|user_id| game.players.contains_key(user_id))
+        };
+        let res = match self.games.get(&msg.game_id) {
+            Some(game) if lobby::is_listed(game) || is_player(game) => Ok(game.public_view()),
+            _ => Err(RelayError::GameNotFound {
+                game_id: msg.game_id,
+            }),
+        };
+        MessageResult(res)
+    }
+}
+
+impl Handler<UserProfile> for RelayServer {
+    type Result = MessageResult<UserProfile>;
+    fn handle(&mut self, msg: UserProfile, _: &mut Context<Self>) -> Self::Result {
+        let user_id = normalize_user_id(&msg.user_id);
+        let res = match self.users.get(&user_id) {
+            Ok(Some(_)) => Ok(UserProfileResult {
+                // users are removed from the map with their last session
+                online: self.sessions.map.contains_key(&user_id),
+                game_id: self
+                    .current_game(&user_id)
+                    .filter(|game_id| self.games.get(game_id).is_some_and(lobby::is_listed)),
+                user_id,
+            }),
+            Ok(None) => Err(RelayError::UserNotFound {
+                user_id: msg.user_id,
+            }),
+            Err(e) => Err(RelayError::Internal(e)),
+        };
+        MessageResult(res)
+    }
+}
+
+impl Handler<GameState> for RelayServer {
+    type Result = MessageResult<GameState>;
+    fn handle(&mut self, msg: GameState, _: &mut Context<Self>) -> Self::Result {
+        let GameState { user_id, game_id } = msg;
+        let res = self
+            .games
+            .get(&game_id)
+            .ok_or(RelayError::GameNotFound { game_id })
+            .and_then(|game| game.player_state(&user_id).map_err(Into::into));
+        MessageResult(res)
     }
 }
 
 impl Handler<PlayerActionRequest> for RelayServer {
-    type Result = ();
+    type Result = MessageResult<PlayerActionRequest>;
     fn handle(&mut self, msg: PlayerActionRequest, ctx: &mut Context<Self>) -> Self::Result {
         let PlayerActionRequest {
             user_id,
@@ -541,45 +1698,49 @@ impl Handler<PlayerActionRequest> for RelayServer {
             action,
         } = msg;
         let mut secret_action = false;
-        let sessions = &self.sessions;
+        let sessions = &mut self.sessions;
         let games = &mut self.games;
         let user_games = &mut self.user_games;
+        let lobby = &mut self.lobby;
         let res = user_games
             .get(&user_id)
-            .ok_or("user games not found".into())
-            .and_then(|user_game_id| {
-                if user_game_id != &game_id {
-                    return Err("user game id invalid".into());
-                }
-                games.get_mut(&game_id).ok_or("game id bad".into())
+            .filter(|user_game_id| *user_game_id == &game_id)
+            .ok_or_else(|| RelayError::NotInGame {
+                game_id: game_id.clone(),
+            })
+            .and_then(|_| {
+                games
+                    .get_mut(&game_id)
+                    .ok_or_else(|| RelayError::GameNotFound {
+                        game_id: game_id.clone(),
+                    })
             })
             .and_then(|game| {
-                game.player_action(&user_id, &action).map(|e| {
-                    // if game is over then remove user_games entry for all players in the game
-                    // stops users from being locked into the game
-                    if game.is_end_phase() {
-                        for user_id in game.players.keys() {
-                            user_games.remove(user_id);
-                            // tell RelayServer to send user new /user_status update through user session
-                            ctx.notify(UserStatus {
-                                user_id: user_id.into(),
-                            });
-                        }
+                let res = game.player_action(&user_id, &action)?;
+                // if game is over then remove user_games entry for all players in the game
+                // stops users from being locked into the game
+                if game.is_end_phase() {
+                    lobby.publish(game);
+                    for user_id in game.players.keys() {
+                        user_games.remove(user_id);
+                        // tell RelayServer to send user new /user_status update through user session
+                        ctx.notify(UserStatus {
+                            user_id: user_id.into(),
+                        });
                     }
-                    // determine whether game update is sent to every player
-                    secret_action = match action {
-                        ActionType::Curse(_) => true,
-                        _ => false,
-                    };
-                    (e, game)
-                })
+                }
+                // determine whether game update is sent to every player
+                secret_action = matches!(action, ActionType::Curse(_));
+                Ok((res, game))
             })
             // TODO rewind game action upon json serialization error
             .and_then(|((res, par), game)| {
-                MsgResult::player_action(&res).map(|json| (json, game, par))
+                MsgResult::player_action(&res)
+                    .map(|json| (json, game, par))
+                    .map_err(RelayError::Internal)
             });
         match res {
-            Err(e) => sessions.send_user(&user_id, &MsgResult::error("player_action", &e)),
+            Err(e) => MessageResult(Err(e)),
             Ok((json, game, par)) => {
                 let PlayerActionResult {
                     action_point_updates,
@@ -604,8 +1765,11 @@ impl Handler<PlayerActionRequest> for RelayServer {
                 } else {
                     sessions.send_user(&user_id, &json);
                 }
+                // secret actions change the version only
+                sessions.send_game_update(game, None);
+                MessageResult(Ok(()))
             }
-        };
+        }
     }
 }
 
@@ -613,15 +1777,19 @@ impl Handler<SpawnTileHeart> for RelayServer {
     type Result = ();
     fn handle(&mut self, msg: SpawnTileHeart, _: &mut Context<Self>) -> Self::Result {
         let SpawnTileHeart { game_id } = msg;
-        let sessions = &self.sessions;
+        let sessions = &mut self.sessions;
         let res = self
             .games
             .get_mut(&game_id)
-            .ok_or("Game not found".to_owned())
+            .ok_or_else(|| RelayError::GameNotFound {
+                game_id: game_id.clone(),
+            })
             .and_then(|game| {
                 let set: (Pos, u32) = game.spawn_tile_heart();
-                let msg = MsgResult::tile_hearts(&game.game_id, set)?;
+                let msg =
+                    MsgResult::tile_hearts(&game.game_id, set).map_err(RelayError::Internal)?;
                 sessions.send_all(game.players.keys(), &msg);
+                sessions.send_game_update(game, None);
                 Ok(())
             });
         if res.is_err() {
@@ -634,13 +1802,15 @@ impl Handler<Replenish> for RelayServer {
     type Result = MessageResult<Replenish>;
     fn handle(&mut self, msg: Replenish, ctx: &mut Context<Self>) -> Self::Result {
         let Replenish { game_id } = msg;
-        let sessions = &self.sessions;
+        let sessions = &mut self.sessions;
         let res = self
             .games
             .get_mut(&game_id)
-            .ok_or("Game not found".to_owned())
+            .ok_or_else(|| RelayError::GameNotFound {
+                game_id: game_id.clone(),
+            })
             .and_then(|game| {
-                let cursed = game.curse_election.get_winners();
+                let cursed = game.curse_election.tally().map_err(GameError::from)?;
                 let apu = game.replenish(&cursed)?;
                 game.curse_election.reset();
                 Ok((game, apu))
@@ -661,6 +1831,7 @@ impl Handler<Replenish> for RelayServer {
                         .unwrap_or_else(|e| MsgResult::error("curse_vote_status", &e));
                     sessions.send_user(&uid, &curse_msg);
                 }
+                sessions.send_game_update(game, None);
                 ctx.notify_later(
                     Replenish { game_id },
                     Duration::from_secs(game.config.turn_time_secs),
@@ -680,3 +1851,326 @@ impl Handler<Replenish> for RelayServer {
         MessageResult(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(sessions: &RelayServerSessions, user_id: &str, last_seq: u64) -> Vec<u64> {
+        let missed = sessions.missed(user_id, last_seq).unwrap();
+        missed.iter().filter_map(|m| m.seq).collect()
+    }
+
+    #[test]
+    fn test_outbox_replay() {
+        let mut sessions = RelayServerSessions::new(OutboundConfig::new());
+        // nothing sent yet, nothing missed
+        assert_eq!(seqs(&sessions, "a", 0), Vec::<u64>::new());
+        for i in 0..3 {
+            sessions.send_user("a", &format!("/alert {}", i));
+        }
+        sessions.send_user("b", "/alert b");
+        assert_eq!(seqs(&sessions, "a", 0), vec![1, 2, 3]);
+        assert_eq!(seqs(&sessions, "a", 2), vec![3]);
+        assert_eq!(seqs(&sessions, "a", 3), Vec::<u64>::new());
+        assert_eq!(seqs(&sessions, "b", 0), vec![1]);
+        // client claims a message that was never sent
+        assert!(sessions.missed("a", 4).is_err());
+
+        // oldest messages fall out of a full outbox
+        for i in 0..MAX_OUTBOX_LEN {
+            sessions.send_user("a", &format!("/alert {}", i));
+        }
+        assert!(matches!(
+            sessions.missed("a", 2),
+            Err(RelayError::ReplayUnavailable {
+                last_seq: 2,
+                oldest_seq: 4
+            })
+        ));
+        assert_eq!(seqs(&sessions, "a", 3).len(), MAX_OUTBOX_LEN);
+
+        // expired messages are purged, then users without sessions are forgotten
+        sessions.purge_outboxes(now_unix_ms() + OUTBOX_TTL_MS + 1000);
+        assert!(sessions.seqs.is_empty());
+        assert!(sessions.missed("a", 3).is_err());
+        assert!(sessions.missed("a", 3 + MAX_OUTBOX_LEN as u64).is_err());
+        // their seqs restart past every seq handed out, so none is reused
+        sessions.send_user("a", "/alert new");
+        assert_eq!(
+            seqs(&sessions, "a", 3 + MAX_OUTBOX_LEN as u64),
+            vec![4 + MAX_OUTBOX_LEN as u64]
+        );
+    }
+
+    fn server() -> RelayServer {
+        RelayServer::new(
+            Box::new(crate::user_store::MemoryUserStore::new()),
+            // cheap hashes keep the tests fast
+            PasswordHasher::new(crate::password::PasswordConfig {
+                memory_kib: 256,
+                iterations: 1,
+                parallelism: 1,
+            })
+            .unwrap(),
+            LoginGuard::new(crate::login_guard::LoginGuardConfig::new()),
+            GameConfig::new(),
+            8,
+            OutboundConfig::new(),
+            MatchmakingConfig::new(),
+        )
+    }
+
+    #[test]
+    fn test_verify_credential() {
+        let mut srv = server();
+        let token = srv.sessions.tokens.issue("a", now_unix()).token;
+        let session = |user_id: &str| Credential::SessionUser {
+            user_id: user_id.into(),
+            token: token.clone(),
+        };
+        assert!(srv.verify_credential(&session("a")).is_ok());
+        assert!(srv
+            .verify_credential(&Credential::BearerToken(token.clone()))
+            .is_ok());
+        assert_eq!(
+            srv.verify_credential(&session("b")),
+            Err(TokenRejection::WrongUser)
+        );
+        // HTTP sessions are cut off with the token they logged in with
+        srv.sessions.tokens.revoke(&token, "a");
+        assert_eq!(
+            srv.verify_credential(&session("a")),
+            Err(TokenRejection::Revoked)
+        );
+    }
+
+    #[test]
+    fn test_login_logs_no_token() {
+        let res = ConnectResult::Success(SuccessResult {
+            user_id: "a".into(),
+            token: Some("secret".into()),
+            token_expires_unix: Some(1),
+            alert: "user exists".into(),
+        });
+        let logged = format!("{:?}", res);
+        assert!(logged.contains("\"a\""));
+        assert!(!logged.contains("secret"));
+    }
+
+    #[actix_rt::test]
+    async fn test_register_and_login() {
+        let srv = server().start();
+        let register = || {
+            srv.send(Register {
+                user_id: "alice".into(),
+                password: "correct horse".into(),
+                addr: None,
+            })
+        };
+        let login = |password: &str| {
+            srv.send(Connect {
+                user_id: "alice".into(),
+                password: password.into(),
+                addr: None,
+                ip: None,
+            })
+        };
+        assert!(matches!(
+            register().await.unwrap(),
+            ConnectResult::Success(_)
+        ));
+        assert!(matches!(
+            register().await.unwrap(),
+            ConnectResult::Fail(Fail::UserExists)
+        ));
+        assert!(matches!(
+            login("correct horse").await.unwrap(),
+            ConnectResult::Success(_)
+        ));
+        assert!(matches!(
+            login("wrong horse").await.unwrap(),
+            ConnectResult::Fail(Fail::Password)
+        ));
+        // the same account whatever the case
+        let res = srv
+            .send(Register {
+                user_id: "Alice".into(),
+                password: "correct horse".into(),
+                addr: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(res, ConnectResult::Fail(Fail::UserExists)));
+        let res = srv
+            .send(Connect {
+                user_id: "ALICE".into(),
+                password: "correct horse".into(),
+                addr: None,
+                ip: None,
+            })
+            .await
+            .unwrap();
+        assert!(matches!(res, ConnectResult::Success(s) if s.user_id == "alice"));
+    }
+
+    #[actix_rt::test]
+    async fn test_unlisted_game_privacy() {
+        let mut srv = server();
+        for user_id in ["a", "b"] {
+            srv.users
+                .insert(User {
+                    user_id: user_id.into(),
+                    password_hash: String::new(),
+                })
+                .unwrap();
+        }
+        let srv = srv.start();
+        let host_game = HostGame {
+            host_user_id: "a".into(),
+            game_id: "g".into(),
+        };
+        srv.send(host_game).await.unwrap().unwrap();
+        let profile = |user_id: &str| {
+            srv.send(UserProfile {
+                user_id: user_id.into(),
+            })
+        };
+        let public_game = |user_id: Option<&str>| {
+            srv.send(PublicGame {
+                game_id: "g".into(),
+                user_id: user_id.map(String::from),
+            })
+        };
+
+        // unlisted games are hidden from everyone but their players
+        assert_eq!(profile("a").await.unwrap().unwrap().game_id, None);
+        for user_id in [None, Some("b")] {
+            assert!(matches!(
+                public_game(user_id).await.unwrap(),
+                Err(RelayError::GameNotFound { .. })
+            ));
+        }
+        assert!(public_game(Some("a")).await.unwrap().is_ok());
+
+        let config_game = ConfigGame {
+            game_id: "g".into(),
+            user_id: "a".into(),
+            op: ConfigGameOp::Visibility(Visibility::Public),
+        };
+        srv.send(config_game).await.unwrap().unwrap();
+        assert_eq!(
+            profile("a").await.unwrap().unwrap().game_id,
+            Some("g".into())
+        );
+        assert!(public_game(None).await.unwrap().is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_game_password_lockout() {
+        let srv = server().start();
+        let host_game = HostGame {
+            host_user_id: "a".into(),
+            game_id: "g".into(),
+        };
+        srv.send(host_game).await.unwrap().unwrap();
+        let config_game = ConfigGame {
+            game_id: "g".into(),
+            user_id: "a".into(),
+            op: ConfigGameOp::Password("pw".into()),
+        };
+        srv.send(config_game).await.unwrap().unwrap();
+        let join = |user_id: &str, password: &str, ip: &str| {
+            srv.send(JoinGame {
+                user_id: user_id.into(),
+                game_id: "g".into(),
+                password: Some(password.into()),
+                ip: Some(ip.into()),
+            })
+        };
+
+        let limits = crate::login_guard::LoginGuardConfig::new();
+        for _ in 0..=limits.per_user.free_attempts {
+            assert!(matches!(
+                join("b", "no", "10.0.0.1").await.unwrap(),
+                Err(RelayError::Game(GameError::WrongPassword))
+            ));
+        }
+        // the guesser is refused even with the right password, from any IP
+        assert!(matches!(
+            join("b", "pw", "10.0.0.9").await.unwrap(),
+            Err(RelayError::JoinLocked { .. })
+        ));
+        // other users with the password still get in
+        assert!(join("c", "pw", "10.0.0.2").await.unwrap().is_ok());
+
+        // guessing as many users from one IP locks out the IP
+        for i in 0..=limits.per_ip.free_attempts {
+            let user_id = format!("guesser{}", i);
+            assert!(join(&user_id, "no", "10.0.0.3").await.unwrap().is_err());
+        }
+        assert!(matches!(
+            join("d", "pw", "10.0.0.3").await.unwrap(),
+            Err(RelayError::JoinLocked { .. })
+        ));
+        assert!(join("d", "pw", "10.0.0.4").await.unwrap().is_ok());
+        // players rejoin regardless
+        assert!(join("a", "", "10.0.0.1").await.unwrap().is_ok());
+    }
+
+    /// session that records the messages it is sent
+    struct Sink(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Actor for Sink {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Sink {
+        type Result = ();
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.text);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_outbound_overflow() {
+        let received = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = received.clone();
+        let addr = Sink::create(|ctx| {
+            ctx.set_mailbox_capacity(2);
+            Sink(sink)
+        });
+        let mut sessions = RelayServerSessions::new(OutboundConfig::new());
+        sessions.track_session("a", addr.clone().recipient(), "t");
+        // nothing is handled until the test yields, the third message overflows the queue
+        for i in 1..=4 {
+            sessions.send_user("a", &format!("/alert {}", i));
+        }
+        assert_eq!(sessions.map["a"][0].lagged_from, Some(3));
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        sessions.send_user("a", "/alert 5");
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["/alert 1", "/alert 2", "/lagged 3", "/alert 5"]
+        );
+        // skipped messages are still kept for resume
+        assert_eq!(sessions.missed("a", 2).unwrap().len(), 3);
+
+        let mut sessions = RelayServerSessions::new(OutboundConfig {
+            on_overflow: Overflow::Disconnect,
+            ..OutboundConfig::new()
+        });
+        received.lock().unwrap().clear();
+        sessions.track_session("a", addr.recipient(), "t");
+        for i in 1..=3 {
+            sessions.send_user("a", &format!("/alert {}", i));
+        }
+        assert!(sessions.map["a"].is_empty());
+        actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["/alert 1", "/alert 2", "/logout lagging"]
+        );
+    }
+}
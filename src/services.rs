@@ -0,0 +1,80 @@
+use actix::prelude::*;
+use actix_session::Session;
+use actix_web::{http::StatusCode, web, Error, HttpRequest, HttpResponse};
+
+use crate::game::GameFilter;
+use crate::protocol::{ErrorPayload, ProtocolError};
+use crate::relay_server::{ListGames, PublicGame, RelayError, RelayServer, UserProfile};
+use crate::ws_session::{credential_user, WsConfig};
+
+/// read-only lobby endpoints, for clients that browse games without a socket
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/games").route(web::get().to(list_games)))
+        .service(web::resource("/games/{game_id}").route(web::get().to(get_game)))
+        .service(web::resource("/users/{user_id}").route(web::get().to(get_user)));
+}
+
+/// listed games matching the query, see `GameFilter`
+async fn list_games(
+    filter: web::Query<GameFilter>,
+    relay_data: web::Data<Addr<RelayServer>>,
+) -> HttpResponse {
+    match relay_data
+        .send(ListGames {
+            filter: filter.into_inner(),
+        })
+        .await
+    {
+        Ok(games) => HttpResponse::Ok().json(games),
+        Err(e) => mailbox_error("games", e),
+    }
+}
+
+/// public view of a game, as sent to its players
+/// unlisted games are only found by their players, logged in like `/ws/`
+async fn get_game(
+    req: HttpRequest,
+    game_id: web::Path<String>,
+    session: Session,
+    relay_data: web::Data<Addr<RelayServer>>,
+    config: web::Data<WsConfig>,
+) -> Result<HttpResponse, Error> {
+    let user_id = credential_user(&req, &session, &relay_data, &config).await?;
+    let game_id = game_id.into_inner();
+    Ok(
+        match relay_data.send(PublicGame { game_id, user_id }).await {
+            Ok(Ok(game)) => HttpResponse::Ok().json(game),
+            Ok(Err(e)) => relay_error("games", e),
+            Err(e) => mailbox_error("games", e),
+        },
+    )
+}
+
+/// public profile and current game of a user, if the game is listed
+async fn get_user(
+    user_id: web::Path<String>,
+    relay_data: web::Data<Addr<RelayServer>>,
+) -> HttpResponse {
+    let user_id = user_id.into_inner();
+    match relay_data.send(UserProfile { user_id }).await {
+        Ok(Ok(profile)) => HttpResponse::Ok().json(profile),
+        Ok(Err(e)) => relay_error("users", e),
+        Err(e) => mailbox_error("users", e),
+    }
+}
+
+/// error as `{context, message, code, details}`, like v2 error envelope payloads
+fn relay_error(context: &str, e: RelayError) -> HttpResponse {
+    let status = match e {
+        RelayError::GameNotFound { .. } | RelayError::UserNotFound { .. } => StatusCode::NOT_FOUND,
+        RelayError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    HttpResponse::build(status).json(ErrorPayload::new(context, &e))
+}
+
+fn mailbox_error(context: &str, e: MailboxError) -> HttpResponse {
+    log::debug!("{:?}", e);
+    let e = ProtocolError::Internal("mailbox error".into());
+    HttpResponse::InternalServerError().json(ErrorPayload::new(context, &e))
+}
@@ -0,0 +1,308 @@
+use actix_redis::RedisSession;
+use actix_service::{boxed, Service, Transform};
+use actix_session::{CookieSession, Session, SessionStatus};
+use actix_web::{
+    cookie::Cookie,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, SET_COOKIE},
+    Error, HttpMessage,
+};
+use futures::future::{ok, FutureExt, LocalBoxFuture};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::token::{gen_token, now_unix};
+
+/// name of the cookie holding the session for every backend
+const SESSION_COOKIE: &str = "actix-session";
+/// how long an untouched in-memory session is kept, matches the redis default
+const MEMORY_SESSION_TTL_SECS: u64 = 60 * 60 * 2;
+
+/// where HTTP session state is kept
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionBackend {
+    /// state stored in redis at address, cookie holds a signed session key
+    Redis(String),
+    /// state stored in the cookie itself, encrypted so clients can't read or alter it
+    Cookie,
+    /// state stored in process memory, lost on restart and not shared between servers
+    Memory,
+}
+
+impl SessionBackend {
+    /// parse backend name `redis`, `cookie` or `memory`
+    pub fn parse(name: &str, redis_addr: &str) -> Result<SessionBackend, String> {
+        match name {
+            "redis" => Ok(SessionBackend::Redis(redis_addr.to_owned())),
+            "cookie" => Ok(SessionBackend::Cookie),
+            "memory" => Ok(SessionBackend::Memory),
+            _ => Err(format!(
+                "unknown session backend {}, expected redis, cookie or memory",
+                name
+            )),
+        }
+    }
+}
+
+/// session state of every client, shared by all workers
+#[derive(Clone, Default)]
+pub struct MemorySessionStore(Arc<Mutex<HashMap<String, MemoryEntry>>>);
+
+struct MemoryEntry {
+    state: HashMap<String, String>,
+    expires_unix: u64,
+}
+
+impl MemorySessionStore {
+    fn load(&self, key: &str, now: u64) -> Option<HashMap<String, String>> {
+        let map = self.0.lock().unwrap();
+        map.get(key)
+            .filter(|e| now < e.expires_unix)
+            .map(|e| e.state.clone())
+    }
+
+    fn save(&self, key: String, state: HashMap<String, String>, now: u64) {
+        let mut map = self.0.lock().unwrap();
+        map.retain(|_, e| now < e.expires_unix);
+        map.insert(
+            key,
+            MemoryEntry {
+                state,
+                expires_unix: now + MEMORY_SESSION_TTL_SECS,
+            },
+        );
+    }
+
+    fn remove(&self, key: &str) {
+        self.0.lock().unwrap().remove(key);
+    }
+}
+
+/// session middleware for the configured backend
+/// construct inside the `HttpServer` factory, `memory` must be shared between workers
+/// the session holds the user's token so its cookie is never readable by scripts
+pub struct SessionMiddleware {
+    backend: SessionBackend,
+    key: Vec<u8>,
+    memory: MemorySessionStore,
+    /// cookie is only sent over HTTPS
+    secure: bool,
+}
+
+impl SessionMiddleware {
+    pub fn new(
+        backend: SessionBackend,
+        key: &[u8],
+        memory: MemorySessionStore,
+        secure: bool,
+    ) -> Self {
+        SessionMiddleware {
+            backend,
+            key: key.to_vec(),
+            memory,
+            secure,
+        }
+    }
+}
+
+impl<S, B> Transform<S> for SessionMiddleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = boxed::BoxService<ServiceRequest, ServiceResponse<B>, Error>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        match &self.backend {
+            SessionBackend::Redis(addr) => RedisSession::new(addr.as_str(), &self.key)
+                .cookie_name(SESSION_COOKIE)
+                .cookie_http_only(true)
+                .cookie_secure(self.secure)
+                .new_transform(service)
+                .map(|res| res.map(boxed::service))
+                .boxed_local(),
+            SessionBackend::Cookie => CookieSession::private(&self.key)
+                .name(SESSION_COOKIE)
+                .secure(self.secure)
+                .http_only(true)
+                .new_transform(service)
+                .map(|res| res.map(boxed::service))
+                .boxed_local(),
+            SessionBackend::Memory => ok(boxed::service(MemorySessionService {
+                service,
+                store: self.memory.clone(),
+                secure: self.secure,
+            }))
+            .boxed_local(),
+        }
+    }
+}
+
+/// keeps session state in a `MemorySessionStore` keyed by a random cookie value
+struct MemorySessionService<S> {
+    service: S,
+    store: MemorySessionStore,
+    secure: bool,
+}
+
+impl<S, B> Service for MemorySessionService<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let now = now_unix();
+        // unknown or expired keys are treated as no session so the client gets a new key
+        let loaded = req.cookie(SESSION_COOKIE).and_then(|c| {
+            let key = c.value().to_owned();
+            self.store.load(&key, now).map(|state| (key, state))
+        });
+        let key = loaded.map(|(key, state)| {
+            Session::set_session(state, &mut req);
+            key
+        });
+        let store = self.store.clone();
+        let secure = self.secure;
+        let fut = self.service.call(req);
+
+        async move {
+            let mut res = fut.await?;
+            match Session::get_changes(&mut res) {
+                (status @ SessionStatus::Changed, Some(state))
+                | (status @ SessionStatus::Renewed, Some(state)) => {
+                    let key = match key {
+                        // renewing issues a fresh key so a key seen before login can't be reused
+                        Some(old) if status == SessionStatus::Renewed => {
+                            store.remove(&old);
+                            gen_token()
+                        }
+                        Some(old) => old,
+                        None => gen_token(),
+                    };
+                    store.save(key.clone(), state.collect(), now);
+                    let cookie = Cookie::build(SESSION_COOKIE, key)
+                        .path("/")
+                        .http_only(true)
+                        .secure(secure)
+                        .finish();
+                    res.response_mut().add_cookie(&cookie)?;
+                }
+                (SessionStatus::Purged, _) => {
+                    if let Some(old) = key {
+                        store.remove(&old);
+                    }
+                    let removal = format!("{}=; Path=/; Max-Age=0", SESSION_COOKIE);
+                    res.headers_mut()
+                        .append(SET_COOKIE, HeaderValue::from_str(&removal)?);
+                }
+                _ => {}
+            }
+            Ok(res)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(
+            SessionBackend::parse("redis", "127.0.0.1:6379"),
+            Ok(SessionBackend::Redis("127.0.0.1:6379".into()))
+        );
+        assert_eq!(
+            SessionBackend::parse("cookie", ""),
+            Ok(SessionBackend::Cookie)
+        );
+        assert_eq!(
+            SessionBackend::parse("memory", ""),
+            Ok(SessionBackend::Memory)
+        );
+        assert!(SessionBackend::parse("disk", "").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_session() {
+        use actix_web::{test, web, App};
+
+        let store = MemorySessionStore::default();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(
+                    SessionBackend::Memory,
+                    &[0; 32],
+                    store.clone(),
+                    true,
+                ))
+                .route(
+                    "/set",
+                    web::get().to(|s: Session| async move {
+                        s.set("user_id", "a").unwrap();
+                        "ok"
+                    }),
+                )
+                .route(
+                    "/get",
+                    web::get().to(|s: Session| async move {
+                        let user_id: Option<String> = s.get("user_id").unwrap();
+                        user_id.unwrap_or_default()
+                    }),
+                )
+                .route(
+                    "/purge",
+                    web::get().to(|s: Session| async move {
+                        s.purge();
+                        "ok"
+                    }),
+                ),
+        )
+        .await;
+
+        let res =
+            test::call_service(&mut app, test::TestRequest::get().uri("/set").to_request()).await;
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|c| c.name() == SESSION_COOKIE)
+            .unwrap()
+            .into_owned();
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+        let req = test::TestRequest::get()
+            .uri("/get")
+            .cookie(cookie.clone())
+            .to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "a");
+
+        let req = test::TestRequest::get()
+            .uri("/purge")
+            .cookie(cookie.clone())
+            .to_request();
+        test::call_service(&mut app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/get")
+            .cookie(cookie)
+            .to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "");
+    }
+}
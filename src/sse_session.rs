@@ -0,0 +1,460 @@
+use crate::{
+    command::{self, connect_ack, mailbox_error, Ack, Client, Nack},
+    common::MsgResult,
+    metrics::Metrics,
+    protocol::{Envelope, ProtocolError, Request},
+    rate_limit::RateLimiter,
+    relay_server::{Attach, Credential, Disconnect, Message, RelayServer, Resume},
+    token::gen_token,
+    ws_session::{credential, credential_user, HeartbeatConfig, WsConfig},
+};
+use actix::prelude::*;
+use actix_session::Session;
+use actix_web::{error::ErrorInternalServerError, web, Error, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures::{channel::mpsc, StreamExt};
+use log::debug;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// header browsers resend the ID of the last event they got with when reconnecting
+const LAST_EVENT_ID: &str = "Last-Event-ID";
+
+/// open event streams by ID, the ID is only known to the client that opened the stream
+#[derive(Default)]
+pub struct SseStreams(Mutex<HashMap<String, Addr<SseSession>>>);
+
+impl SseStreams {
+    fn get(&self, stream_id: &str) -> Option<Addr<SseSession>> {
+        self.0.lock().unwrap().get(stream_id).cloned()
+    }
+}
+
+/// event envelope of a push, numbered messages carry their seq as the event ID
+/// compact JSON has no newlines so the envelope fits a single `data:` line
+fn sse_event(msg: &Message) -> String {
+    let event = Envelope::from_legacy(&msg.text).sequenced(msg.seq, msg.sent_unix_ms);
+    match msg.seq {
+        Some(seq) => format!("id: {}\ndata: {}\n\n", seq, event.to_json()),
+        None => format!("data: {}\n\n", event.to_json()),
+    }
+}
+
+/// session of a client behind a proxy that breaks websockets
+/// pushes are streamed as server-sent events and commands are posted to `/events/{stream_id}`
+pub struct SseSession {
+    stream_id: String,
+    /// event stream of the HTTP response, closed once the client goes away
+    tx: mpsc::Sender<Bytes>,
+    /// relay server
+    server_addr: Addr<RelayServer>,
+    user_id: Option<String>,
+    /// credential from the stream request used to log in once the session starts
+    credential: Option<Credential>,
+    /// last numbered message the client got before reconnecting
+    last_seq: Option<u64>,
+    /// IP address of the client
+    ip: Option<String>,
+    heartbeat: HeartbeatConfig,
+    /// pushes queued beyond this make the relay server treat the session as too slow
+    queue_len: usize,
+    rate_limiter: RateLimiter,
+    metrics: web::Data<Metrics>,
+    streams: web::Data<SseStreams>,
+}
+
+impl SseSession {
+    /// comment the client ignores to keep proxies from closing an idle stream
+    /// also notices clients that went away
+    fn hb(&self, ctx: &mut Context<Self>) {
+        let interval = Duration::from_secs(self.heartbeat.interval_secs);
+        ctx.run_interval(interval, |act, ctx| {
+            act.write(Bytes::from_static(b": ping\n\n"), ctx);
+        });
+    }
+
+    fn client(&self, ctx: &mut Context<Self>) -> Client {
+        Client {
+            server: self.server_addr.clone(),
+            addr: ctx.address().recipient(),
+            user_id: self.user_id.clone(),
+            ip: self.ip.clone(),
+            // there's no `/hello` for event streams
+            features: vec![],
+        }
+    }
+
+    fn push(&mut self, msg: Message, ctx: &mut Context<Self>) {
+        self.write(Bytes::from(sse_event(&msg)), ctx);
+    }
+
+    /// stop once the client is gone or has stopped reading
+    fn write(&mut self, bytes: Bytes, ctx: &mut Context<Self>) {
+        if let Err(e) = self.tx.try_send(bytes) {
+            debug!("[srv/s] {:?} EVENT STREAM CLOSED {}", self.user_id, e);
+            ctx.stop();
+        }
+    }
+
+    /// resend what the client missed while reconnecting, after logging in
+    fn resume(&mut self, ctx: &mut Context<Self>) {
+        let (user_id, last_seq) = match (self.user_id.clone(), self.last_seq.take()) {
+            (Some(user_id), Some(last_seq)) => (user_id, last_seq),
+            _ => return,
+        };
+        self.server_addr
+            .send(Resume {
+                user_id,
+                addr: ctx.address().recipient(),
+                last_seq,
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                let e = match res {
+                    Ok(Ok(_)) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(e) => Some(mailbox_error(e).message),
+                };
+                if let Some(e) = e {
+                    act.push(Message::new(MsgResult::error("resume", &e)), ctx);
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.set_mailbox_capacity(self.queue_len);
+        self.streams
+            .0
+            .lock()
+            .unwrap()
+            .insert(self.stream_id.clone(), ctx.address());
+        self.push(Message::new(MsgResult::event_stream(&self.stream_id)), ctx);
+        self.hb(ctx);
+        // log in with the credential the stream was opened with, before handling any command
+        if let Some(credential) = self.credential.take() {
+            self.server_addr
+                .send(Attach {
+                    credential,
+                    addr: ctx.address().recipient(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    let res = res.map_err(mailbox_error).and_then(connect_ack);
+                    if let Ok(Ack {
+                        user_id: Some(user_id),
+                        ..
+                    }) = &res
+                    {
+                        act.user_id = Some(user_id.clone());
+                    }
+                    let text = match res {
+                        Ok(ack) => ack.legacy,
+                        Err(nack) => Some(MsgResult::error(&nack.context, &nack.message)),
+                    };
+                    if let Some(text) = text {
+                        act.push(Message::new(text), ctx);
+                    }
+                    act.resume(ctx);
+                    fut::ready(())
+                })
+                .wait(ctx);
+        }
+    }
+
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        debug!("[srv/s] {:?} SSE SESSION STOPPING", self.user_id);
+        self.streams.0.lock().unwrap().remove(&self.stream_id);
+        // notify relay server
+        if let Some(user_id) = self.user_id.clone() {
+            self.server_addr.do_send(Disconnect {
+                user_id,
+                addr: ctx.address().recipient(),
+            });
+        }
+        Running::Stop
+    }
+}
+
+/// Handle messages from relay server, streamed to the client as events
+impl Handler<Message> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
+        // relay server no longer tracks this session for the user
+        if msg.text.starts_with("/logout") {
+            self.user_id = None;
+        }
+        self.push(msg, ctx);
+    }
+}
+
+/// user the stream is logged in as
+#[derive(Message)]
+#[rtype(result = "Option<String>")]
+struct StreamUser;
+
+impl Handler<StreamUser> for SseSession {
+    type Result = Option<String>;
+
+    fn handle(&mut self, _: StreamUser, _: &mut Self::Context) -> Self::Result {
+        self.user_id.clone()
+    }
+}
+
+/// command posted for the session, run as if it came over a websocket
+#[derive(Message)]
+#[rtype(result = "Result<Ack, Nack>")]
+pub struct Command(pub Request);
+
+impl Handler<Command> for SseSession {
+    type Result = ResponseActFuture<Self, Result<Ack, Nack>>;
+
+    fn handle(&mut self, Command(req): Command, ctx: &mut Self::Context) -> Self::Result {
+        match self
+            .rate_limiter
+            .admit(&req.kind, Instant::now(), &self.metrics)
+        {
+            Ok(()) => (),
+            Err(ProtocolError::Flooding) => {
+                debug!("[srv/s] {:?} FLOODING, DISCONNECTING", self.user_id);
+                ctx.stop();
+                let nack = Nack::new("rate_limit", &ProtocolError::Flooding);
+                return Box::pin(fut::ready(Err(nack)));
+            }
+            Err(e) => return Box::pin(fut::ready(Err(Nack::new("rate_limit", &e)))),
+        }
+        Box::pin(
+            command::dispatch(self.client(ctx), req)
+                .into_actor(self)
+                .map(|res, act, _| {
+                    // adopt user logged in by the command
+                    if let Ok(Ack {
+                        user_id: Some(user_id),
+                        ..
+                    }) = &res
+                    {
+                        act.user_id = Some(user_id.clone());
+                    }
+                    res
+                }),
+        )
+    }
+}
+
+/// opens an event stream, logged in already if the request carries
+/// a bearer token or an HTTP session from `/login`
+/// the first event has the stream ID commands are posted with
+pub async fn events_route(
+    req: HttpRequest,
+    session: Session,
+    srv: web::Data<Addr<RelayServer>>,
+    config: web::Data<WsConfig>,
+    metrics: web::Data<Metrics>,
+    streams: web::Data<SseStreams>,
+) -> Result<HttpResponse, Error> {
+    let credential = credential(&req, &session, &srv, &config).await?;
+    let last_seq = req
+        .headers()
+        .get(LAST_EVENT_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let (tx, rx) = mpsc::channel(config.outbound.queue_len);
+    SseSession {
+        stream_id: gen_token(),
+        tx,
+        server_addr: srv.get_ref().clone(),
+        user_id: None,
+        credential,
+        last_seq,
+        ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        heartbeat: config.heartbeat.clone(),
+        queue_len: config.outbound.queue_len,
+        rate_limiter: RateLimiter::new(config.rate_limit.clone(), Instant::now()),
+        metrics,
+        streams,
+    }
+    .start();
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        // keep reverse proxies from buffering the stream
+        .header("X-Accel-Buffering", "no")
+        .streaming(rx.map(Ok::<_, Error>)))
+}
+
+/// runs a v2 request envelope for an event stream, replying with its ack or error envelope
+/// pushes caused by the command are sent on the stream
+/// the stream ID ends up in access logs so posts to logged in streams need the same
+/// credential as `events_route`, for the stream's user
+/// a `login` or `register` posted to an anonymous stream logs the poster's HTTP session in too,
+/// so browsers keep posting with their cookie, other clients send the acked token as a bearer token
+pub async fn command_route(
+    req: HttpRequest,
+    stream_id: web::Path<String>,
+    body: String,
+    session: Session,
+    srv: web::Data<Addr<RelayServer>>,
+    config: web::Data<WsConfig>,
+    streams: web::Data<SseStreams>,
+) -> Result<HttpResponse, Error> {
+    let addr = match streams.get(&stream_id) {
+        Some(addr) => addr,
+        None => {
+            let e = ProtocolError::BadRequest("unknown event stream".into());
+            let nack = Nack::new("session", &e);
+            return Ok(HttpResponse::NotFound().json(Envelope::error(None, nack)));
+        }
+    };
+    let caller = credential_user(&req, &session, &srv, &config).await?;
+    if addr
+        .send(StreamUser)
+        .await
+        .map_err(ErrorInternalServerError)?
+        != caller
+    {
+        let e = ProtocolError::BadRequest("event stream belongs to another user".into());
+        let nack = Nack::new("session", &e);
+        return Ok(HttpResponse::Forbidden().json(Envelope::error(None, nack)));
+    }
+    let req = match Request::from_envelope(&body) {
+        Ok(req) => req,
+        Err((id, e)) => {
+            let nack = Nack::new("session", &e);
+            return Ok(HttpResponse::BadRequest().json(Envelope::error(id, nack)));
+        }
+    };
+    let id = req.id.clone().unwrap_or(Value::Null);
+    let envelope = match addr.send(Command(req)).await {
+        Ok(Ok(ack)) => {
+            if let (Some(user_id), Some(token)) = (&ack.user_id, ack.payload["token"].as_str()) {
+                session.set("user_id", user_id)?;
+                session.set("token", token)?;
+                session.renew();
+            }
+            Envelope::Ack {
+                id,
+                payload: ack.payload,
+            }
+        }
+        Ok(Err(nack)) => Envelope::error(Some(id), nack),
+        Err(e) => Envelope::error(Some(id), mailbox_error(e)),
+    };
+    Ok(HttpResponse::Ok().json(envelope))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::login_guard::LoginGuard;
+    use crate::password::{PasswordConfig, PasswordHasher};
+    use crate::session_backend::{MemorySessionStore, SessionBackend, SessionMiddleware};
+    use crate::user_store::MemoryUserStore;
+    use actix_web::{test, App};
+
+    #[test]
+    fn test_sse_event() {
+        let msg = Message {
+            text: MsgResult::event_stream("abc"),
+            seq: None,
+            sent_unix_ms: 1000,
+        };
+        assert_eq!(
+            sse_event(&msg),
+            "data: {\"type\":\"event\",\"event\":\"event_stream\",\"payload\":{\"stream_id\":\"abc\"},\"sent_unix_ms\":1000}\n\n"
+        );
+        let msg = Message {
+            text: "/error resume: no\nnewlines".into(),
+            seq: Some(7),
+            sent_unix_ms: 1000,
+        };
+        let event = sse_event(&msg);
+        assert!(event.starts_with("id: 7\ndata: {"));
+        assert!(event.ends_with("}\n\n"));
+        assert_eq!(event.matches('\n').count(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_login_binds_http_session() {
+        let config = Config::new();
+        let relay = RelayServer::new(
+            Box::new(MemoryUserStore::new()),
+            PasswordHasher::new(PasswordConfig {
+                memory_kib: 256,
+                iterations: 1,
+                parallelism: 1,
+            })
+            .unwrap(),
+            LoginGuard::new(config.login_guard.clone()),
+            config.game.clone(),
+            config.board_size,
+            config.outbound.clone(),
+            config.matchmaking.clone(),
+        )
+        .start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(SessionMiddleware::new(
+                    SessionBackend::Memory,
+                    &[0; 32],
+                    MemorySessionStore::default(),
+                    true,
+                ))
+                .data(relay)
+                .data(WsConfig {
+                    heartbeat: config.heartbeat.clone(),
+                    protocol: config.protocol.clone(),
+                    outbound: config.outbound.clone(),
+                    rate_limit: config.rate_limit.clone(),
+                    allowed_origins: config.cors.allowed_origins.clone(),
+                })
+                .app_data(web::Data::new(Metrics::default()))
+                .app_data(web::Data::new(SseStreams::default()))
+                .route("/events", web::get().to(events_route))
+                .route("/events/{stream_id}", web::post().to(command_route)),
+        )
+        .await;
+
+        // open an anonymous stream, its first event has the stream ID
+        let req = test::TestRequest::get().uri("/events").to_request();
+        let mut res = test::call_service(&mut app, req).await;
+        // the stream stops once its body is dropped
+        let mut events = res.take_body();
+        let first = events.next().await.unwrap().unwrap();
+        let first = std::str::from_utf8(&first).unwrap();
+        let event: Value = serde_json::from_str(first.trim().trim_start_matches("data: ")).unwrap();
+        let uri = format!(
+            "/events/{}",
+            event["payload"]["stream_id"].as_str().unwrap()
+        );
+        let post = |body: &str| {
+            test::TestRequest::post()
+                .uri(&uri)
+                .set_payload(body.to_owned())
+        };
+
+        let body = r#"{"id":1,"type":"register","payload":{"user_id":"alice","password":"correct horse"}}"#;
+        let res = test::call_service(&mut app, post(body).to_request()).await;
+        let cookie = res.response().cookies().next().unwrap().into_owned();
+        let ack: Value = test::read_body_json(res).await;
+        assert_eq!(ack["type"], "ack");
+
+        // later posts carry the stream's user in the cookie set by the login
+        let body = r#"{"id":2,"type":"user_status"}"#;
+        let res = test::call_service(&mut app, post(body).cookie(cookie).to_request()).await;
+        let ack: Value = test::read_body_json(res).await;
+        assert_eq!(ack["type"], "ack");
+        assert_eq!(ack["id"], 2);
+        // and without it the stream is someone else's
+        let res = test::call_service(&mut app, post(body).to_request()).await;
+        assert_eq!(res.status(), 403);
+    }
+}
@@ -0,0 +1,228 @@
+use rand::{rngs::OsRng, RngCore};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// number of characters in a session token, 6 bits each
+pub const TOKEN_LEN: usize = 32;
+/// how long a session token is valid for after being issued or refreshed
+pub const TOKEN_TTL_SECS: u64 = 60 * 60 * 24;
+
+/// url safe alphabet, 64 characters so every random byte maps without bias
+const TOKEN_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// generate a cryptographically random session token
+pub fn gen_token() -> String {
+    let mut bytes = [0u8; TOKEN_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|b| TOKEN_CHARSET[(*b & 63) as usize] as char)
+        .collect()
+}
+
+/// seconds since the unix epoch
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// milliseconds since the unix epoch
+pub fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionToken {
+    pub token: String,
+    pub user_id: String,
+    pub issued_unix: u64,
+    pub expires_unix: u64,
+    #[serde(skip_serializing)]
+    revoked: bool,
+}
+
+/// reason a session token was not accepted, serialized as `{code}`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "code")]
+pub enum TokenRejection {
+    #[serde(rename = "token_unknown")]
+    Unknown,
+    #[serde(rename = "token_expired")]
+    Expired,
+    #[serde(rename = "token_revoked")]
+    Revoked,
+    /// token belongs to a different user than the session
+    #[serde(rename = "token_wrong_user")]
+    WrongUser,
+}
+
+impl Display for TokenRejection {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            TokenRejection::Unknown => write!(f, "token unknown"),
+            TokenRejection::Expired => write!(f, "token expired"),
+            TokenRejection::Revoked => write!(f, "token revoked"),
+            TokenRejection::WrongUser => write!(f, "token belongs to another user"),
+        }
+    }
+}
+
+/// issued session tokens indexed by token
+/// revoked tokens are kept until they expire so they are rejected as revoked rather than unknown
+pub struct TokenStore {
+    tokens: HashMap<String, SessionToken>,
+    ttl_secs: u64,
+}
+
+impl TokenStore {
+    pub fn new(ttl_secs: u64) -> TokenStore {
+        TokenStore {
+            tokens: HashMap::new(),
+            ttl_secs,
+        }
+    }
+
+    /// issue a new token for user valid from `now`
+    pub fn issue(&mut self, user_id: &str, now: u64) -> SessionToken {
+        let token = SessionToken {
+            token: gen_token(),
+            user_id: user_id.to_owned(),
+            issued_unix: now,
+            expires_unix: now + self.ttl_secs,
+            revoked: false,
+        };
+        self.tokens.insert(token.token.clone(), token.clone());
+        token
+    }
+
+    /// check token is known, unexpired, unrevoked and, if given, belongs to `user_id`
+    pub fn verify(
+        &self,
+        token: &str,
+        user_id: Option<&str>,
+        now: u64,
+    ) -> Result<&SessionToken, TokenRejection> {
+        let found = self.tokens.get(token).ok_or(TokenRejection::Unknown)?;
+        if let Some(user_id) = user_id {
+            if found.user_id != user_id {
+                return Err(TokenRejection::WrongUser);
+            }
+        }
+        if found.revoked {
+            return Err(TokenRejection::Revoked);
+        }
+        if now >= found.expires_unix {
+            return Err(TokenRejection::Expired);
+        }
+        Ok(found)
+    }
+
+    /// exchange a valid token for a new one, revoking the old token
+    pub fn refresh(
+        &mut self,
+        token: &str,
+        user_id: &str,
+        now: u64,
+    ) -> Result<SessionToken, TokenRejection> {
+        self.verify(token, Some(user_id), now)?;
+        self.revoke(token, user_id);
+        Ok(self.issue(user_id, now))
+    }
+
+    /// revoke token of user, returns the revoked token if it was unrevoked and owned by user
+    pub fn revoke(&mut self, token: &str, user_id: &str) -> Option<SessionToken> {
+        self.tokens.get_mut(token).and_then(|t| {
+            if t.revoked || t.user_id != user_id {
+                return None;
+            }
+            t.revoked = true;
+            Some(t.clone())
+        })
+    }
+
+    /// revoke every token of user
+    pub fn revoke_user(&mut self, user_id: &str) {
+        for t in self.tokens.values_mut() {
+            if t.user_id == user_id {
+                t.revoked = true;
+            }
+        }
+    }
+
+    /// forget tokens that have expired
+    pub fn purge_expired(&mut self, now: u64) {
+        self.tokens.retain(|_, t| now < t.expires_unix);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gen_token() {
+        let a = gen_token();
+        assert_eq!(a.len(), TOKEN_LEN);
+        assert!(a.bytes().all(|b| TOKEN_CHARSET.contains(&b)));
+        assert_ne!(a, gen_token());
+    }
+
+    #[test]
+    fn test_token_lifecycle() {
+        let mut store = TokenStore::new(100);
+        let t = store.issue("a", 1000);
+        assert_eq!(t.expires_unix, 1100);
+        assert_eq!(store.verify(&t.token, Some("a"), 1050), Ok(&t));
+        assert_eq!(store.verify(&t.token, None, 1050), Ok(&t));
+        assert_eq!(
+            store.verify(&t.token, Some("b"), 1050),
+            Err(TokenRejection::WrongUser)
+        );
+        assert_eq!(
+            store.verify(&t.token, Some("a"), 1100),
+            Err(TokenRejection::Expired)
+        );
+        assert_eq!(
+            store.verify("nope", Some("a"), 1050),
+            Err(TokenRejection::Unknown)
+        );
+
+        // refreshing revokes old token and extends expiry
+        let r = store.refresh(&t.token, "a", 1090).unwrap();
+        assert_eq!(r.expires_unix, 1190);
+        assert_eq!(
+            store.verify(&t.token, Some("a"), 1091),
+            Err(TokenRejection::Revoked)
+        );
+        assert!(store.verify(&r.token, Some("a"), 1150).is_ok());
+        assert_eq!(
+            store.refresh(&t.token, "a", 1091),
+            Err(TokenRejection::Revoked)
+        );
+
+        // only the owner can revoke a token
+        assert_eq!(store.revoke(&r.token, "b"), None);
+        assert!(store.verify(&r.token, Some("a"), 1150).is_ok());
+
+        // revoking by user
+        store.revoke_user("a");
+        assert_eq!(
+            store.verify(&r.token, Some("a"), 1150),
+            Err(TokenRejection::Revoked)
+        );
+
+        // expired tokens are forgotten
+        store.purge_expired(1190);
+        assert_eq!(
+            store.verify(&r.token, Some("a"), 1150),
+            Err(TokenRejection::Unknown)
+        );
+    }
+}
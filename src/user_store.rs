@@ -0,0 +1,220 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// stored user account, password is kept as a salted hash only
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct User {
+    pub user_id: String,
+    pub password_hash: String,
+}
+
+/// storage of user accounts used by `RelayServer`
+pub trait UserStore {
+    /// get user by ID, `None` if no such user exists
+    fn get(&self, user_id: &str) -> Result<Option<User>, String>;
+    /// insert new user or replace existing user with the same ID
+    fn insert(&mut self, user: User) -> Result<(), String>;
+}
+
+/// user accounts kept in memory, lost when the process exits
+pub struct MemoryUserStore {
+    users: HashMap<String, User>,
+}
+
+impl MemoryUserStore {
+    pub fn new() -> MemoryUserStore {
+        MemoryUserStore {
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl UserStore for MemoryUserStore {
+    fn get(&self, user_id: &str) -> Result<Option<User>, String> {
+        Ok(self.users.get(user_id).cloned())
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), String> {
+        self.users.insert(user.user_id.clone(), user);
+        Ok(())
+    }
+}
+
+/// user accounts kept in memory and persisted to a log of JSON lines, one per change
+/// later lines replace earlier lines for the same user, each line is synced before `insert` returns
+/// opening compacts the log to one line per user, written to a temporary sibling then renamed
+/// so a crash never loses accounts already stored
+pub struct FileUserStore {
+    path: PathBuf,
+    users: HashMap<String, User>,
+    /// log opened for appending
+    log: File,
+}
+
+impl FileUserStore {
+    /// load users from `path`, starting empty if the file doesn't exist yet
+    /// files holding a JSON array of users are read too and rewritten as a log
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<FileUserStore, String> {
+        let path = path.into();
+        let err = |e: &dyn Display| format!("{}: {}", path.display(), e);
+        let users = match fs::read(&path) {
+            Ok(bytes) => parse_users(&bytes)
+                .map_err(|e| err(&e))?
+                .into_iter()
+                .map(|user| (user.user_id.clone(), user))
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(err(&e)),
+        };
+        compact(&path, &users).map_err(|e| err(&e))?;
+        let log = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_err(|e| err(&e))?;
+        Ok(FileUserStore { path, users, log })
+    }
+
+    fn append(&mut self, user: &User) -> Result<(), String> {
+        let mut line = serde_json::to_vec(user).map_err(|e| format!("{}", e))?;
+        line.push(b'\n');
+        let log = &mut self.log;
+        let len = log.metadata().map(|m| m.len());
+        let res = len.and_then(|len| {
+            log.write_all(&line)
+                .and_then(|_| log.sync_data())
+                .inspect_err(|_| {
+                    // cut off what was written so later lines don't follow a partial one
+                    let _ = log.set_len(len);
+                })
+        });
+        res.map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+/// users of a log, or of a JSON array of users
+/// a last line cut short by a crash while appending is dropped
+fn parse_users(bytes: &[u8]) -> Result<Vec<User>, serde_json::Error> {
+    if bytes.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[') {
+        return serde_json::from_slice(bytes);
+    }
+    let mut users = vec![];
+    let mut lines = bytes.split(|b| *b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        match serde_json::from_slice(line) {
+            Ok(user) => users.push(user),
+            Err(e) if lines.peek().is_none() => {
+                warn!("dropping incomplete last user store line: {}", e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(users)
+}
+
+/// replace the file at `path` with a log of one line per user
+/// written and synced to a temporary sibling then renamed, so the file is always complete
+fn compact(path: &Path, users: &HashMap<String, User>) -> std::io::Result<()> {
+    let mut users: Vec<&User> = users.values().collect();
+    users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+    let mut log = vec![];
+    for user in users {
+        serde_json::to_writer(&mut log, user)?;
+        log.push(b'\n');
+    }
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&log)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // sync the directory so the rename itself survives a crash
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+impl UserStore for FileUserStore {
+    fn get(&self, user_id: &str) -> Result<Option<User>, String> {
+        Ok(self.users.get(user_id).cloned())
+    }
+
+    fn insert(&mut self, user: User) -> Result<(), String> {
+        self.append(&user)?;
+        self.users.insert(user.user_id.clone(), user);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(user_id: &str, password_hash: &str) -> User {
+        User {
+            user_id: user_id.into(),
+            password_hash: password_hash.into(),
+        }
+    }
+
+    #[test]
+    fn test_file_store_persists() -> Result<(), String> {
+        let path = std::env::temp_dir().join(format!("bftt_users_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = FileUserStore::open(&path)?;
+        assert_eq!(store.get("a")?, None);
+        store.insert(user("a", "hash_a"))?;
+        store.insert(user("b", "hash_b"))?;
+        store.insert(user("a", "hash_a2"))?;
+
+        // reopening the file sees every change
+        let store = FileUserStore::open(&path)?;
+        assert_eq!(store.get("a")?, Some(user("a", "hash_a2")));
+        assert_eq!(store.get("b")?, Some(user("b", "hash_b")));
+        assert_eq!(store.get("c")?, None);
+
+        fs::remove_file(&path).map_err(|e| format!("{}", e))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_recovers() -> Result<(), String> {
+        let path = std::env::temp_dir().join(format!("bftt_users_old_{}.json", std::process::id()));
+        let line = |user: &User| serde_json::to_string(user).unwrap();
+
+        // users files from before the log are read and rewritten as one
+        let array = serde_json::to_vec(&[user("a", "hash_a")]).unwrap();
+        fs::write(&path, array).map_err(|e| format!("{}", e))?;
+        let store = FileUserStore::open(&path)?;
+        assert_eq!(store.get("a")?, Some(user("a", "hash_a")));
+        let log = fs::read_to_string(&path).map_err(|e| format!("{}", e))?;
+        assert_eq!(log, format!("{}\n", line(&user("a", "hash_a"))));
+
+        // a line cut short by a crash is dropped
+        let log = format!("{}\n{{\"user_id\":\"b\",\"pass", line(&user("a", "hash_a")));
+        fs::write(&path, log).map_err(|e| format!("{}", e))?;
+        let mut store = FileUserStore::open(&path)?;
+        assert_eq!(store.get("b")?, None);
+        store.insert(user("b", "hash_b"))?;
+        let store = FileUserStore::open(&path)?;
+        assert_eq!(store.get("a")?, Some(user("a", "hash_a")));
+        assert_eq!(store.get("b")?, Some(user("b", "hash_b")));
+
+        // anywhere else it's an error rather than lost accounts
+        let log = format!("{{\"user_id\"\n{}\n", line(&user("a", "hash_a")));
+        fs::write(&path, log).map_err(|e| format!("{}", e))?;
+        assert!(FileUserStore::open(&path).is_err());
+
+        fs::remove_file(&path).map_err(|e| format!("{}", e))?;
+        Ok(())
+    }
+}
@@ -1,17 +1,29 @@
 use crate::{
-    common::{Identity, MsgResult},
-    game::PlayerAction,
+    command::{self, connect_ack, mailbox_error, Ack, Client, Nack},
+    common::MsgResult,
+    metrics::Metrics,
+    protocol::{
+        deflate, inflate, Encoding, Envelope, Hello, ProtocolConfig, ProtocolError, Request,
+        DEFLATE, ENVELOPE_VERSION, LEGACY_VERSION,
+    },
+    rate_limit::{RateLimitConfig, RateLimiter},
     relay_server::{
-        ConfigGame, Connect, ConnectResult, Disconnect, HostGame, JoinGame, Message,
-        PlayerActionRequest, RelayServer, StartGame, User, UserStatus, VerifySession,
+        Attach, CheckCredential, Credential, Disconnect, Message, OutboundConfig, RelayServer,
     },
+    token::TokenRejection,
 };
 use actix::prelude::*;
-use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_session::Session;
+use actix_web::{
+    error::{ErrorForbidden, ErrorInternalServerError, ErrorUnauthorized},
+    http::header,
+    web, Error, HttpRequest, HttpResponse,
+};
 use actix_web_actors::ws;
-use log::debug;
+use log::{debug, warn};
 use serde::Deserialize;
-use serde_json::from_slice;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use ws::WebsocketContext as WSctx;
 
@@ -20,28 +32,72 @@ pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
 /// How long before lack of client response causes a timeout
 pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
 
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default = "HeartbeatConfig::new", deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// seconds between pings
+    pub interval_secs: u64,
+    /// seconds without a client response before the session is closed
+    pub client_timeout_secs: u64,
+}
+
+impl HeartbeatConfig {
+    pub fn new() -> HeartbeatConfig {
+        HeartbeatConfig {
+            interval_secs: HEARTBEAT_INTERVAL.as_secs(),
+            client_timeout_secs: CLIENT_TIMEOUT.as_secs(),
+        }
+    }
+}
+
+/// settings shared by every websocket session
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    pub heartbeat: HeartbeatConfig,
+    pub protocol: ProtocolConfig,
+    pub outbound: OutboundConfig,
+    pub rate_limit: RateLimitConfig,
+    /// origins of pages allowed to log in with the HTTP session, see `CorsConfig`
+    pub allowed_origins: Vec<String>,
+}
+
 pub struct WsSession {
     /// hb increment
     hb: Instant,
     /// relay server
     server_addr: Addr<RelayServer>,
     user_id: Option<String>,
-}
-
-fn from_json<'a, T>(des: &'a str) -> Result<T, String>
-where
-    T: Deserialize<'a>,
-{
-    from_slice::<T>(des.as_bytes()).map_err(|err| (format!("{:?}", err)))
+    /// credential from the upgrade request used to log in once the session starts
+    credential: Option<Credential>,
+    /// IP address of the client
+    ip: Option<String>,
+    heartbeat: HeartbeatConfig,
+    /// protocol version the client speaks, see `protocol`
+    version: u32,
+    /// version is settled by `/hello` or the client's first message, later envelopes keep it
+    settled: bool,
+    /// frame encoding, binary encodings are only sent and accepted in binary frames
+    encoding: Encoding,
+    /// frames are deflate compressed and sent as binary, see `protocol::DEFLATE`
+    deflate: bool,
+    /// optional features negotiated with `/hello`
+    features: Vec<String>,
+    protocol: ProtocolConfig,
+    /// pushes queued beyond this make the relay server treat the session as too slow
+    queue_len: usize,
+    rate_limiter: RateLimiter,
+    metrics: web::Data<Metrics>,
 }
 
 impl WsSession {
     // helper method that sends intermittent ping to client
     // also checks ws client heartbeat and terminates session on timeout
     fn hb(&self, ctx: &mut WSctx<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        let interval = Duration::from_secs(self.heartbeat.interval_secs);
+        ctx.run_interval(interval, |act, ctx| {
             // check client hearbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            let timeout = Duration::from_secs(act.heartbeat.client_timeout_secs);
+            if Instant::now().duration_since(act.hb) > timeout {
                 // heartbeat timed out
                 debug!("[srv/s] {:?} TIMED OUT, DISCONNECTING", &act.user_id);
 
@@ -55,190 +111,201 @@ impl WsSession {
         });
     }
 
-    /// get the ws session's user_id otherwise return login error
-    fn clone_user_id(&self) -> Result<String, String> {
-        self.user_id
-            .clone()
-            .ok_or_else(|| "user not logged in".into())
+    fn client(&self, ctx: &mut WSctx<Self>) -> Client {
+        Client {
+            server: self.server_addr.clone(),
+            addr: ctx.address().recipient(),
+            user_id: self.user_id.clone(),
+            ip: self.ip.clone(),
+            features: self.features.clone(),
+        }
     }
 
-    fn mailbox_check<M>(
-        &mut self,
-        msg: Result<M, MailboxError>,
-        ctx: &mut WSctx<Self>,
-    ) -> Result<M, ()> {
-        match msg {
-            Ok(m) => Ok(m),
-            Err(e) => {
-                ctx.text(MsgResult::error("server", "mailbox error"));
-                debug!("{:?}", e);
-                Err(())
+    /// run request, replying once it completes
+    /// later client messages wait so replies are sent in request order
+    fn handle_request(&mut self, req: Request, ctx: &mut WSctx<Self>) {
+        match self
+            .rate_limiter
+            .admit(&req.kind, Instant::now(), &self.metrics)
+        {
+            Ok(()) => (),
+            Err(ProtocolError::Flooding) => {
+                debug!("[srv/s] {:?} FLOODING, DISCONNECTING", self.user_id);
+                return self.refuse(req.id, "rate_limit", ProtocolError::Flooding, ctx);
             }
+            Err(e) => return self.reply(req.id, Err(Nack::new("rate_limit", &e)), ctx),
         }
-    }
-
-    fn relay_connect(&mut self, msg: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let id = from_json::<Identity>(&msg)?;
-        let addr = ctx.address().recipient();
-        let user_id = id.user_id.clone();
-        self.server_addr
-            .send(Connect {
-                addr: Some(addr),
-                user: User {
-                    user_id: id.user_id,
-                    password: id.password,
-                },
-            })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                let res = act.mailbox_check(res, ctx);
-                if let Ok(res) = res {
-                    match res {
-                        ConnectResult::Fail(_) => {
-                            ctx.text(MsgResult::error("user", "FailPassword"));
-                        }
-                        ConnectResult::Success(s) => {
-                            act.user_id = Some(user_id);
-                            ctx.text(
-                                MsgResult::login(&s)
-                                    .unwrap_or_else(|e| MsgResult::error("login", &e)),
-                            );
-                        }
-                    }
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
-        Ok(())
-    }
-
-    fn verify_session(&mut self, token: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        self.server_addr
-            .send(VerifySession {
-                user_id: self.user_id.clone(),
-                addr: ctx.address().recipient(),
-                token,
-            })
-            .into_actor(self)
-            .then(|_, _, _| fut::ready(()))
-            .wait(ctx);
-        Ok(())
-    }
-
-    fn host_game(&self, game_id: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let host_user_id = self.clone_user_id()?;
-        self.server_addr
-            .send(HostGame {
-                game_id,
-                host_user_id,
-            })
+        if req.kind == "hello" {
+            return self.hello(req, ctx);
+        }
+        if self.version < self.protocol.min_version {
+            let e = ProtocolError::UnsupportedVersion {
+                version: self.version,
+                min_version: self.protocol.min_version,
+            };
+            return self.refuse(req.id, "hello", e, ctx);
+        }
+        let id = req.id.clone();
+        command::dispatch(self.client(ctx), req)
             .into_actor(self)
             .then(|res, act, ctx| {
-                if let Ok(res) = act.mailbox_check(res, ctx) {
-                    if let Err(msg) = res {
-                        ctx.text(MsgResult::error("server", msg.as_str()));
-                    }
-                }
+                act.reply(id, res, ctx);
                 fut::ready(())
             })
             .wait(ctx);
-        Ok(())
     }
 
-    fn join_game(&self, game_id: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let user_id = self.clone_user_id()?;
-        self.server_addr
-            .send(JoinGame { game_id, user_id })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                if let Ok(res) = act.mailbox_check(res, ctx) {
-                    if let Err(msg) = res {
-                        ctx.text(MsgResult::error("server", msg.as_str()));
+    /// negotiate protocol version, encoding and features with the client
+    /// clients too old for the server are refused and disconnected
+    fn hello(&mut self, req: Request, ctx: &mut WSctx<Self>) {
+        let hello = match req.payload::<Hello>() {
+            Ok(hello) => hello,
+            Err(e) => return self.reply(req.id, Err(Nack::new("hello", &e)), ctx),
+        };
+        match hello.negotiate(&self.protocol) {
+            Ok(reply) => {
+                let ack = serde_json::to_value(&reply)
+                    .map_err(|e| e.to_string())
+                    .and_then(|payload| {
+                        Ok(Ack {
+                            payload,
+                            legacy: Some(MsgResult::hello(&reply)?),
+                            user_id: None,
+                        })
+                    });
+                match ack {
+                    Ok(ack) => {
+                        // reply in the encoding the hello was sent in before switching
+                        self.reply(req.id, Ok(ack), ctx);
+                        self.version = reply.version;
+                        self.settled = true;
+                        self.encoding = Encoding::parse(&reply.encoding)
+                            .expect("negotiated encoding is supported");
+                        self.deflate = reply.features.iter().any(|f| f == DEFLATE);
+                        self.features = reply.features;
                     }
+                    Err(e) => self.refuse(req.id, "hello", ProtocolError::Internal(e), ctx),
                 }
-                fut::ready(())
-            })
-            .wait(ctx);
-        Ok(())
+            }
+            Err(e) => self.refuse(req.id, "hello", e, ctx),
+        }
     }
 
-    fn conf_game(&self, msg: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let user_id = self.clone_user_id()?;
-        let des = from_json::<ConfigGame>(&msg)?;
-        self.server_addr
-            .send(ConfigGame {
-                op: des.op,
-                game_id: des.game_id,
-                user_id,
-            })
-            .into_actor(self)
-            .then(|_, _, _| fut::ready(()))
-            .wait(ctx);
-        Ok(())
+    /// reply to request with an error then close the session
+    fn refuse(
+        &mut self,
+        id: Option<Value>,
+        context: &str,
+        e: ProtocolError,
+        ctx: &mut WSctx<Self>,
+    ) {
+        self.reply(id, Err(Nack::new(context, &e)), ctx);
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(e.to_string()),
+        }));
+        ctx.stop();
     }
 
-    fn start_game(&self, game_id: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let user_id = self.clone_user_id()?;
-
-        self.server_addr
-            .send(StartGame { game_id, user_id })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                if let Ok(res) = act.mailbox_check(res, ctx) {
-                    if let Err(msg) = res {
-                        ctx.text(MsgResult::error("server", msg.as_str()));
-                    }
+    /// adopt user logged in by a command and reply to its request
+    /// legacy requests have no ID and get a legacy reply, if any
+    fn reply(&mut self, id: Option<Value>, res: Result<Ack, Nack>, ctx: &mut WSctx<Self>) {
+        if let Ok(Ack {
+            user_id: Some(user_id),
+            ..
+        }) = &res
+        {
+            self.user_id = Some(user_id.clone());
+        }
+        match (id, res) {
+            (None, Ok(ack)) => {
+                if let Some(text) = ack.legacy {
+                    ctx.text(text);
                 }
-                fut::ready(())
-            })
-            .wait(ctx);
-        Ok(())
+            }
+            (None, Err(nack)) => ctx.text(MsgResult::error(&nack.context, &nack.message)),
+            (Some(id), Ok(ack)) => self.send(
+                Envelope::Ack {
+                    id,
+                    payload: ack.payload,
+                },
+                ctx,
+            ),
+            (Some(id), Err(nack)) => self.send(Envelope::error(Some(id), nack), ctx),
+        }
     }
 
-    fn user_status(&self, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let user_id = self.clone_user_id()?;
-        self.server_addr
-            .send(UserStatus { user_id })
-            .into_actor(self)
-            .then(|_, _, _| fut::ready(()))
-            .wait(ctx);
-        Ok(())
+    /// send a legacy `/event argument` push, converted to an event envelope for v2 clients
+    /// legacy clients aren't sent `/game_update` patches, they get full games instead
+    fn push(&self, msg: Message, ctx: &mut WSctx<Self>) {
+        if self.version >= ENVELOPE_VERSION {
+            let event = Envelope::from_legacy(&msg.text).sequenced(msg.seq, msg.sent_unix_ms);
+            self.send(event, ctx);
+        } else if !msg.text.starts_with("/game_update ") {
+            ctx.text(msg.text);
+        }
     }
 
-    fn player_action(&self, msg: String, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let user_id = self.clone_user_id()?;
-        let des = from_json::<PlayerAction>(&msg)?;
-        self.server_addr
-            .send(PlayerActionRequest {
-                action: des.action,
-                game_id: des.game_id,
-                user_id,
-            })
-            .into_actor(self)
-            .then(|_, _, _| fut::ready(()))
-            .wait(ctx);
-        Ok(())
+    /// send envelope as text or, for binary encodings, as a binary frame
+    fn send(&self, envelope: Envelope, ctx: &mut WSctx<Self>) {
+        if !self.encoding.is_binary() && !self.deflate {
+            return ctx.text(envelope.to_json());
+        }
+        match self.encoding.encode(&envelope) {
+            Ok(bytes) if self.deflate => ctx.binary(deflate(&bytes)),
+            Ok(bytes) => ctx.binary(bytes),
+            Err(e) => debug!("[srv/s] {:?} ENCODING FAILED {}", self.user_id, e),
+        }
     }
 
-    /// parses command and payload to forward onto function, returning any error
-    fn parse_message(&mut self, text: &str, ctx: &mut WSctx<Self>) -> Result<(), String> {
-        let m = text.trim();
-        let v: Vec<&str> = m.splitn(2, ' ').collect();
-        let cmd = v.get(0).ok_or_else(|| "invalid command")?;
-        let mut msg = String::new();
-        if v.len() == 2 {
-            msg = v[1].clone().into();
+    /// parse client text as a v2 envelope if it is a JSON object, otherwise as a legacy command
+    /// an envelope as the first message switches the session's pushes to events
+    fn parse_message(&mut self, text: &str, ctx: &mut WSctx<Self>) {
+        let first = !std::mem::replace(&mut self.settled, true);
+        if self.encoding.is_binary() {
+            let e = ProtocolError::BadRequest("expected a binary frame".into());
+            self.send(Envelope::error(None, Nack::new("session", &e)), ctx);
+        } else if text.trim_start().starts_with('{') {
+            if first {
+                self.version = ENVELOPE_VERSION;
+            }
+            match Request::from_envelope(text) {
+                Ok(req) => self.handle_request(req, ctx),
+                Err((id, e)) => self.send(Envelope::error(id, Nack::new("session", &e)), ctx),
+            }
+        } else {
+            match Request::from_legacy(text) {
+                Ok(req) => self.handle_request(req, ctx),
+                Err(e) if self.version >= ENVELOPE_VERSION => {
+                    self.send(Envelope::error(None, Nack::new("session", &e)), ctx)
+                }
+                Err(e) => ctx.text(MsgResult::error("session", &e.to_string())),
+            }
         }
-        match *cmd {
-            "/login" => self.relay_connect(msg, ctx),
-            "/verify" => self.verify_session(msg, ctx),
-            "/host_game" => self.host_game(msg, ctx),
-            "/join_game" => self.join_game(msg, ctx),
-            "/conf_game" => self.conf_game(msg, ctx),
-            "/start_game" => self.start_game(msg, ctx),
-            "/user_status" => self.user_status(ctx),
-            "/player_action" => self.player_action(msg, ctx),
-            _ => Err(format!("unknown command type {:?}", m).to_owned()),
+    }
+
+    /// decode a binary frame as an envelope in the session's binary encoding
+    fn parse_binary(&mut self, bytes: &[u8], ctx: &mut WSctx<Self>) {
+        let req = if self.deflate {
+            inflate(bytes)
+                .and_then(|bytes| self.encoding.decode(&bytes))
+                .map_err(|e| (None, ProtocolError::BadRequest(e)))
+                .and_then(Request::from_value)
+        } else if self.encoding.is_binary() {
+            self.encoding
+                .decode(bytes)
+                .map_err(|e| (None, ProtocolError::BadRequest(e)))
+                .and_then(Request::from_value)
+        } else {
+            let e = "binary frames require a binary encoding or deflate, negotiate one with /hello";
+            Err((None, ProtocolError::BadRequest(e.into())))
+        };
+        match req {
+            Ok(req) => self.handle_request(req, ctx),
+            Err((id, e)) if self.version >= ENVELOPE_VERSION => {
+                self.send(Envelope::error(id, Nack::new("session", &e)), ctx)
+            }
+            Err((_, e)) => ctx.text(MsgResult::error("session", &e.to_string())),
         }
     }
 }
@@ -249,15 +316,49 @@ impl Actor for WsSession {
     // Method is called on actor start
     // register ws session with RelayServer
     fn started(&mut self, ctx: &mut Self::Context) {
+        // pushes queue here while the client is slow to read, bounded so the relay server notices
+        ctx.set_mailbox_capacity(self.queue_len);
         // start heartbeat with ws client
         self.hb(ctx);
+        // log in with the credential the client upgraded with, before handling any client message
+        if let Some(credential) = self.credential.take() {
+            self.server_addr
+                .send(Attach {
+                    credential,
+                    addr: ctx.address().recipient(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    // not a client request so the result is pushed rather than replied
+                    let res = res.map_err(mailbox_error).and_then(connect_ack);
+                    if let Ok(Ack {
+                        user_id: Some(user_id),
+                        ..
+                    }) = &res
+                    {
+                        act.user_id = Some(user_id.clone());
+                    }
+                    let text = match res {
+                        Ok(ack) => ack.legacy,
+                        Err(nack) => Some(MsgResult::error(&nack.context, &nack.message)),
+                    };
+                    if let Some(text) = text {
+                        act.push(Message::new(text), ctx);
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx);
+        }
     }
 
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         debug!("[srv/s] {:?} WS SESSION STOPPING", self.user_id);
         // notify relay server
         if let Some(user_id) = self.user_id.clone() {
-            self.server_addr.do_send(Disconnect { user_id });
+            self.server_addr.do_send(Disconnect {
+                user_id,
+                addr: ctx.address().recipient(),
+            });
         }
         Running::Stop
     }
@@ -268,7 +369,11 @@ impl Handler<Message> for WsSession {
     type Result = ();
 
     fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        // relay server no longer tracks this session for the user
+        if msg.text.starts_with("/logout") {
+            self.user_id = None;
+        }
+        self.push(msg, ctx);
     }
 }
 
@@ -290,12 +395,8 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
                 ctx.ping(&msg);
             }
             ws::Message::Pong(_) => self.hb = Instant::now(),
-            ws::Message::Text(text) => {
-                self.parse_message(&text, ctx).unwrap_or_else(|err| {
-                    ctx.text(MsgResult::error("session", err.as_str()));
-                });
-            }
-            ws::Message::Binary(_) => println!("[srv/s] Unexpected binary"),
+            ws::Message::Text(text) => self.parse_message(&text, ctx),
+            ws::Message::Binary(bytes) => self.parse_binary(&bytes, ctx),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -306,18 +407,179 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     }
 }
 
+/// get session token from `Authorization: Bearer` header
+/// never from the query string, which ends up in access logs, browsers use their HTTP session instead
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_owned())
+}
+
+/// browsers send the session cookie with upgrades from any site and CORS doesn't apply to them,
+/// so only pages from allowed origins may use it, clients that aren't browsers send no `Origin`
+fn origin_allowed(req: &HttpRequest, allowed_origins: &[String]) -> bool {
+    match req.headers().get(header::ORIGIN) {
+        None => true,
+        Some(origin) => {
+            let allowed = allowed_origins
+                .iter()
+                .any(|o| o.as_bytes() == origin.as_bytes());
+            if !allowed {
+                warn!("rejected HTTP session login from origin {:?}", origin);
+            }
+            allowed
+        }
+    }
+}
+
+/// credential to log a new session in with, a bearer token or the token of an HTTP session from `/login`
+/// HTTP sessions are refused unless their token is still valid, so revoking it cuts off the browser too
+/// and unless the request comes from an allowed origin
+pub async fn credential(
+    req: &HttpRequest,
+    session: &Session,
+    srv: &Addr<RelayServer>,
+    config: &WsConfig,
+) -> Result<Option<Credential>, Error> {
+    if let Some(token) = bearer_token(req) {
+        return Ok(Some(Credential::BearerToken(token)));
+    }
+    let user_id = match session.get::<String>("user_id")? {
+        Some(user_id) => user_id,
+        None => return Ok(None),
+    };
+    if !origin_allowed(req, &config.allowed_origins) {
+        return Err(ErrorForbidden("origin not allowed"));
+    }
+    let token = session
+        .get::<String>("token")?
+        .ok_or_else(|| ErrorUnauthorized(TokenRejection::Unknown))?;
+    let credential = Credential::SessionUser { user_id, token };
+    srv.send(CheckCredential(credential.clone()))
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(ErrorUnauthorized)?;
+    Ok(Some(credential))
+}
+
+/// user of the request's credential, `None` for anonymous requests
+pub async fn credential_user(
+    req: &HttpRequest,
+    session: &Session,
+    srv: &Addr<RelayServer>,
+    config: &WsConfig,
+) -> Result<Option<String>, Error> {
+    match credential(req, session, srv, config).await? {
+        Some(credential) => Ok(Some(
+            srv.send(CheckCredential(credential))
+                .await
+                .map_err(ErrorInternalServerError)?
+                .map_err(ErrorUnauthorized)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// protocol version requested with the `protocol` query parameter, legacy by default
+/// clients can also switch to v2 by sending an envelope
+fn protocol_version(req: &HttpRequest) -> u32 {
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.get("protocol").and_then(|v| v.parse().ok()))
+        .filter(|v| *v == ENVELOPE_VERSION)
+        .unwrap_or(LEGACY_VERSION)
+}
+
+/// encoding requested with the `encoding` query parameter, JSON by default
+/// binary encodings imply v2 envelopes
+fn requested_encoding(req: &HttpRequest) -> Encoding {
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.get("encoding").and_then(|v| Encoding::parse(v)))
+        .unwrap_or(Encoding::Json)
+}
+
+/// `compression=deflate` query parameter enables `deflate` from the start, implying v2 envelopes
+fn requested_deflate(req: &HttpRequest) -> bool {
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.get("compression").map(|v| v == DEFLATE))
+        .unwrap_or(false)
+}
+
+/// upgrades to a websocket session, logged in already if the request carries
+/// a bearer token or an HTTP session from `/login`
 pub async fn ws_route(
     req: HttpRequest,
     stream: web::Payload,
+    session: Session,
     srv: web::Data<Addr<RelayServer>>,
+    config: web::Data<WsConfig>,
+    metrics: web::Data<Metrics>,
 ) -> Result<HttpResponse, Error> {
+    let credential = credential(&req, &session, &srv, &config).await?;
+    let encoding = requested_encoding(&req);
+    let deflate = requested_deflate(&req);
+    let version = match encoding.is_binary() || deflate {
+        true => ENVELOPE_VERSION,
+        false => protocol_version(&req),
+    };
     ws::start(
         WsSession {
             hb: Instant::now(),
             server_addr: srv.get_ref().clone(),
             user_id: None,
+            credential,
+            ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            heartbeat: config.heartbeat.clone(),
+            version,
+            settled: false,
+            encoding,
+            deflate,
+            features: vec![],
+            protocol: config.protocol.clone(),
+            queue_len: config.outbound.queue_len,
+            rate_limiter: RateLimiter::new(config.rate_limit.clone(), Instant::now()),
+            metrics,
         },
         &req,
         stream,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_bearer_token() {
+        let req = TestRequest::default()
+            .header(header::AUTHORIZATION, "Bearer abc")
+            .to_http_request();
+        assert_eq!(bearer_token(&req), Some("abc".into()));
+        // tokens in the query string would be logged with the request line
+        let req = TestRequest::with_uri("/ws/?access_token=abc").to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = vec!["http://localhost:3000".to_owned()];
+        let req = TestRequest::default()
+            .header(header::ORIGIN, "http://localhost:3000")
+            .to_http_request();
+        assert!(origin_allowed(&req, &allowed));
+        let req = TestRequest::default()
+            .header(header::ORIGIN, "https://evil.example")
+            .to_http_request();
+        assert!(!origin_allowed(&req, &allowed));
+        // clients that aren't browsers
+        assert!(origin_allowed(
+            &TestRequest::default().to_http_request(),
+            &allowed
+        ));
+    }
+}